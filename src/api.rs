@@ -1,18 +1,30 @@
+use crate::bindings::Tokenizer;
+use crate::grammar::{choice_grammar, classify_prompt};
+use crate::images::{
+    downscale_encoded, fnv1a, normalize, BoxedImageLoader, FsImageLoader, ImageLoader,
+};
+use crate::transcript::{TranscriptLogger, TranscriptRecord};
 use async_stream::try_stream;
 use base64::{engine::general_purpose, Engine as _};
 use bon::bon;
 use color_eyre::Result;
 use futures_util::{Stream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::io::Write;
 use std::path::Path;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::task::{Context, Poll};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::fs;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::sync::oneshot;
 use tokio_util::io::StreamReader;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
 use tracing::info;
 
 #[derive(Error, Debug)]
@@ -27,7 +39,74 @@ pub enum LlamaError {
     Api {
         status: reqwest::StatusCode,
         body: String,
+        /// `body` parsed as an OpenAI-style `{"error": {...}}` object, if it is one.
+        detail: Option<ApiErrorDetail>,
     },
+    #[error("Response exceeded the {limit} byte limit")]
+    ResponseTooLarge { limit: usize },
+    #[error("Request timed out")]
+    Timeout,
+    #[error("Request body is {estimated} bytes, over the {limit} byte limit")]
+    RequestTooLarge { estimated: usize, limit: usize },
+    #[error("All endpoints failed: {}", describe_failures(.0))]
+    AllEndpointsFailed(Vec<(String, Self)>),
+    #[error("Model refused: {0}")]
+    Refusal(String),
+    /// The model answered with nothing but whitespace (and no tool calls). The question is
+    /// taken out of the history again, so it can be retried.
+    #[error("Model returned an empty response")]
+    EmptyResponse,
+    #[error("Image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("Classification needs at least one label")]
+    NoLabels,
+    /// `classify` got an answer that isn't one of its labels.
+    #[error("Model answered {0:?}, which is not one of the labels")]
+    UnknownLabel(String),
+    /// `count_prompt_tokens` needs a client built with a `tokenizer`.
+    #[error("No local tokenizer configured")]
+    NoTokenizer,
+    #[error("Tokenizer error: {0}")]
+    Tokenizer(String),
+}
+
+/// The `error` object llama-server sends with a failed request.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiErrorDetail {
+    pub message: String,
+    /// E.g. `"exceed_context_size_error"`.
+    #[serde(rename = "type", default)]
+    pub kind: Option<String>,
+    /// llama-server sends the HTTP status here; other OpenAI-compatible servers send a
+    /// string such as `"context_length_exceeded"`.
+    #[serde(default)]
+    pub code: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    error: ApiErrorDetail,
+}
+
+async fn api_error(response: reqwest::Response) -> LlamaError {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    let detail = serde_json::from_str::<ApiErrorBody>(&body)
+        .ok()
+        .map(|b| b.error);
+    LlamaError::Api {
+        status,
+        body,
+        detail,
+    }
+}
+
+fn describe_failures(failures: &[(String, LlamaError)]) -> String {
+    failures
+        .iter()
+        .map(|(url, e)| format!("{url}: {e}"))
+        .collect::<Vec<_>>()
+        .join("; ")
 }
 
 pub type LlamaResult<T> = Result<T, LlamaError>;
@@ -36,6 +115,104 @@ pub type LlamaResult<T> = Result<T, LlamaError>;
 pub struct Message {
     pub role: String,
     pub content: MessageContent,
+    /// Calls requested by an assistant turn.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// On `"tool"` messages: the call this is the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// A function the model may call. llama-server needs `--jinja` for tool support.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolDefinition {
+    Function { function: FunctionDefinition },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON schema of the arguments object.
+    pub parameters: serde_json::Value,
+}
+
+impl ToolDefinition {
+    pub fn function(
+        name: impl Into<String>,
+        description: Option<String>,
+        parameters: serde_json::Value,
+    ) -> Self {
+        Self::Function {
+            function: FunctionDefinition {
+                name: name.into(),
+                description,
+                parameters,
+            },
+        }
+    }
+}
+
+/// A tool invocation from the model. `arguments` holds the parsed JSON, or the raw text as
+/// a JSON string when the model produced invalid JSON.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "WireToolCall", into = "WireToolCall")]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+// OpenAI's shape, where the arguments are a JSON-encoded string.
+#[derive(Serialize, Deserialize, Default)]
+struct WireToolCall {
+    #[serde(default)]
+    id: String,
+    #[serde(rename = "type", default = "function_type")]
+    kind: String,
+    function: WireFunction,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct WireFunction {
+    name: String,
+    #[serde(default)]
+    arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+impl From<WireToolCall> for ToolCall {
+    fn from(wire: WireToolCall) -> Self {
+        let arguments = serde_json::from_str(&wire.function.arguments)
+            .unwrap_or(serde_json::Value::String(wire.function.arguments));
+        Self {
+            id: wire.id,
+            name: wire.function.name,
+            arguments,
+        }
+    }
+}
+
+impl From<ToolCall> for WireToolCall {
+    fn from(call: ToolCall) -> Self {
+        let arguments = match call.arguments {
+            serde_json::Value::String(raw) => raw,
+            value => value.to_string(),
+        };
+        Self {
+            id: call.id,
+            kind: function_type(),
+            function: WireFunction {
+                name: call.name,
+                arguments,
+            },
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -54,6 +231,25 @@ pub enum MessagePart {
     ImageUrl { image_url: ImageUrl },
 }
 
+impl MessagePart {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text { text: text.into() }
+    }
+
+    /// An inline base64 image. The type is sniffed from the bytes, falling back to
+    /// `mime_type`. JPEG, PNG, BMP and GIF are sent as they are and WebP as PNG; other
+    /// formats are rejected, since llama-server can't decode them.
+    pub fn image(bytes: &[u8], mime_type: Option<&str>) -> LlamaResult<Self> {
+        let (bytes, mime_type) = normalize(bytes, mime_type)?;
+        let b64 = general_purpose::STANDARD.encode(bytes);
+        Ok(Self::ImageUrl {
+            image_url: ImageUrl {
+                url: format!("data:{mime_type};base64,{b64}"),
+            },
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ImageUrl {
     pub url: String,
@@ -64,62 +260,396 @@ struct ChatRequest {
     model: String,
     messages: Vec<Message>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
     temperature: f32,
     top_p: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    typical_p: Option<f32>,
     repetition_penalty: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repeat_last_n: Option<i32>,
     presence_penalty: f32,
+    cache_prompt: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grammar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolDefinition>,
+    // Ordered, so the same request always serializes (and fingerprints) the same.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    logit_bias: BTreeMap<i32, f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_logprobs: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+/// OpenAI-style `response_format`, asking the server for guaranteed JSON.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    JsonObject,
+    /// `json_schema` is sent as-is, e.g. `{"name": "caption", "schema": {...}}`.
+    JsonSchema {
+        json_schema: serde_json::Value,
+    },
 }
 
 #[derive(Deserialize)]
 struct ChatChunk {
     choices: Vec<ChunkChoice>,
+    /// Only on the last chunk, which has no choices, when usage was requested.
+    #[serde(default)]
+    usage: Option<Usage>,
 }
 
 #[derive(Deserialize)]
 struct ChunkChoice {
     delta: ChunkDelta,
+    finish_reason: Option<String>,
+    #[serde(default)]
+    logprobs: Option<ChoiceLogprobs>,
 }
 
 #[derive(Deserialize)]
 struct ChunkDelta {
     content: Option<String>,
     reasoning_content: Option<String>,
+    refusal: Option<String>,
+    tool_calls: Option<Vec<ToolCallDelta>>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+// Streamed tool calls arrive in fragments: the id and name first, then the arguments
+// string piece by piece, all keyed by `index`.
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    #[serde(default)]
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+fn merge_tool_call(calls: &mut Vec<WireToolCall>, fragment: ToolCallDelta) {
+    if calls.len() <= fragment.index {
+        calls.resize_with(fragment.index + 1, WireToolCall::default);
+    }
+    let call = &mut calls[fragment.index];
+    if let Some(id) = fragment.id {
+        call.id = id;
+    }
+    if let Some(function) = fragment.function {
+        call.function.name += &function.name.unwrap_or_default();
+        call.function.arguments += &function.arguments.unwrap_or_default();
+    }
 }
 
 #[derive(Deserialize)]
 pub struct ChatFullResponse {
     pub choices: Vec<FullChoice>,
+    /// Seed the server sampled with, when it reports one (useful to replay a generation
+    /// that used a server-chosen random seed).
+    #[serde(default)]
+    pub seed: Option<u32>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// llama-server's own performance counters.
+    #[serde(default)]
+    pub timings: Option<Timings>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timings {
+    pub prompt_n: u32,
+    pub prompt_ms: f64,
+    pub prompt_per_second: f64,
+    pub predicted_n: u32,
+    pub predicted_ms: f64,
+    pub predicted_per_second: f64,
+}
+
+/// Everything known about a finished `chat` call besides its content.
+#[derive(Debug, Clone, Default)]
+pub struct RequestMetadata {
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+    /// Generation speed as measured by the server.
+    pub tokens_per_sec: Option<f64>,
+    pub prompt_tokens_per_sec: Option<f64>,
+    /// Time the server spent generating, excluding prompt processing.
+    pub generation_time: Option<Duration>,
+    /// Wall-clock time of the whole call, including upload and prompt processing.
+    pub elapsed: Duration,
+    pub finish_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct FullChoice {
     pub message: FullMessage,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+    /// Only when the client asks for `logprobs`.
+    #[serde(default)]
+    pub logprobs: Option<ChoiceLogprobs>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ChoiceLogprobs {
+    #[serde(default)]
+    pub content: Option<Vec<Logprob>>,
+}
+
+/// A generated token with its log-probability, as reported by the server.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Logprob {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+    /// The most likely tokens at this position, when `top_logprobs` was requested.
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
 }
 
 #[derive(Deserialize)]
 pub struct FullMessage {
+    #[serde(default)]
+    pub role: Option<String>,
     pub content: Option<String>,
+    /// The model's thinking, when the server splits it from `content`.
+    #[serde(default)]
+    pub reasoning_content: Option<String>,
+    #[serde(default)]
+    pub refusal: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
 }
 
 #[derive(Debug, Clone)]
 pub enum ChatEvent {
     Content(String),
     Reasoning(String),
+    /// Only emitted when refusal detection is enabled on the client.
+    Refusal(String),
+    /// A complete tool call, emitted once all its argument fragments have arrived.
+    ToolCall(ToolCall),
+    /// Logprobs of the tokens in the preceding `Content`, when the client asks for them.
+    Logprobs(Vec<Logprob>),
+    Unknown(serde_json::Value),
+    /// Token counts for the whole request, sent by the server after the answer.
+    Usage {
+        prompt_tokens: u32,
+        completion_tokens: u32,
+    },
+    /// Last event of a completed stream; `finish_reason` is e.g. `"stop"` or `"length"`.
+    Done {
+        finish_reason: Option<String>,
+    },
 }
 
 #[derive(Clone)]
 pub struct LlamaClient {
     http: reqwest::Client,
-    base_url: String,
+    endpoints: Arc<Endpoints>,
     config: LlamaConfig,
+    tokenizer: Option<Arc<Tokenizer>>,
+}
+
+/// Order in which a client with several base URLs tries them for each request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailoverStrategy {
+    /// Always start at the first URL, moving on only when it fails.
+    #[default]
+    PrimaryWithFallback,
+    /// Rotate the starting URL per request to spread load.
+    RoundRobin,
+}
+
+const ENDPOINT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Retries for requests that failed on every endpoint with a connection error, a timeout
+/// or a 5xx.
+///
+/// This covers e.g. llama-server still loading its model or stalled on a long queue. 4xx responses are never retried,
+/// and streams only retry the initial request, never mid-stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for every retry after it.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Fail on the first error.
+    pub const fn disabled() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+fn is_transient(error: &LlamaError) -> bool {
+    match error {
+        LlamaError::Http(e) => e.is_connect(),
+        LlamaError::Timeout => true,
+        LlamaError::Api { status, .. } => status.is_server_error(),
+        LlamaError::AllEndpointsFailed(failures) => failures.iter().any(|(_, e)| is_transient(e)),
+        _ => false,
+    }
+}
+
+// llama-server answers 503 while it's still loading its model.
+fn is_loading(error: &LlamaError) -> bool {
+    match error {
+        LlamaError::Api { status, .. } => *status == reqwest::StatusCode::SERVICE_UNAVAILABLE,
+        LlamaError::AllEndpointsFailed(failures) => failures.iter().any(|(_, e)| is_loading(e)),
+        _ => false,
+    }
+}
+
+struct Endpoints {
+    urls: Vec<String>,
+    strategy: FailoverStrategy,
+    next: AtomicUsize,
+    down_until: Mutex<Vec<Option<Instant>>>,
+}
+
+impl Endpoints {
+    // Endpoints that recently failed go to the back, so they're still tried as a last resort.
+    fn order(&self) -> Vec<usize> {
+        let n = self.urls.len();
+        let start = match self.strategy {
+            FailoverStrategy::PrimaryWithFallback => 0,
+            FailoverStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % n,
+        };
+        let now = Instant::now();
+        let down_until = self
+            .down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (mut up, down): (Vec<usize>, Vec<usize>) = (0..n)
+            .map(|i| (start + i) % n)
+            .partition(|&i| down_until[i].is_none_or(|until| until <= now));
+        up.extend(down);
+        up
+    }
+
+    fn mark(&self, index: usize, healthy: bool) {
+        let mut down_until = self
+            .down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        down_until[index] = if healthy {
+            None
+        } else {
+            Some(Instant::now() + ENDPOINT_COOLDOWN)
+        };
+    }
 }
 
 #[derive(Clone)]
 pub struct LlamaConfig {
+    /// Model for requests that don't name one.
+    pub model: Option<String>,
     pub temperature: f32,
     pub top_p: f32,
+    pub top_k: Option<i32>,
+    pub typical_p: Option<f32>,
     pub repetition_penalty: f32,
+    /// How many recent tokens the penalties look at; `-1` for the whole context.
+    pub repeat_last_n: Option<i32>,
     pub presence_penalty: f32,
+    pub emit_unknown_deltas: bool,
+    pub cache_prompt: bool,
+    pub seed: Option<u32>,
+    pub request_timeout: Option<Duration>,
+    pub stream_idle_timeout: Option<Duration>,
+    pub detect_refusals: bool,
+    pub refusal_patterns: Vec<String>,
+    pub max_request_bytes: Option<usize>,
+    pub response_format: Option<ResponseFormat>,
+    pub retry: RetryPolicy,
+    pub logit_bias: HashMap<i32, f32>,
+    /// Alternatives to return per token when logprobs are requested (`Some(0)` for just
+    /// the sampled token's); `None` requests no logprobs.
+    pub logprobs: Option<u8>,
+}
+
+impl LlamaConfig {
+    fn is_refusal(&self, content: &str) -> bool {
+        let content = content.trim_start().to_lowercase();
+        self.refusal_patterns
+            .iter()
+            .any(|pattern| content.starts_with(&pattern.to_lowercase()))
+    }
+}
+
+/// Per-request values that take precedence over the client's `LlamaConfig`.
+#[derive(Debug, Clone, Default)]
+pub struct RequestOverrides {
+    pub temperature: Option<f32>,
+    pub seed: Option<u32>,
+    /// GBNF grammar llama-server constrains the answer to.
+    pub grammar: Option<String>,
+    pub response_format: Option<ResponseFormat>,
+    pub tools: Vec<ToolDefinition>,
+    /// Completions to generate; `ChatFullResponse::choices` then holds one per completion.
+    pub n: Option<u32>,
 }
 
 #[bon]
@@ -127,37 +657,222 @@ impl LlamaClient {
     #[builder(start_fn = with_base_url)]
     pub fn new(
         #[builder(start_fn)] base_url: &str,
+        /// Used by requests that pass an empty model name, and by sessions built without
+        /// one.
+        model: Option<String>,
+        /// Extra servers to fail over to on connection errors or 5xx responses.
+        fallback_urls: Option<Vec<String>>,
+        failover: Option<FailoverStrategy>,
         temperature: Option<f32>,
         top_p: Option<f32>,
+        /// Sent only when set; otherwise the server's default applies.
+        top_k: Option<i32>,
+        /// Sent only when set; otherwise the server's default applies.
+        typical_p: Option<f32>,
         repetition_penalty: Option<f32>,
+        /// Penalty window in tokens, `-1` for the whole context. Sent only when set;
+        /// otherwise the server's default applies.
+        repeat_last_n: Option<i32>,
         presence_penalty: Option<f32>,
-    ) -> Self {
-        Self {
-            http: reqwest::Client::new(),
-            base_url: base_url.to_string(),
+        emit_unknown_deltas: Option<bool>,
+        /// Lets llama-server reuse the KV cache for the shared prompt prefix between
+        /// requests (default `true`).
+        cache_prompt: Option<bool>,
+        seed: Option<u32>,
+        connect_timeout: Option<Duration>,
+        /// Total time allowed for a non-streaming request. Streams use
+        /// `stream_idle_timeout` instead so long generations aren't cut off.
+        request_timeout: Option<Duration>,
+        /// Maximum gap between two SSE lines before a stream fails with
+        /// `LlamaError::Timeout`.
+        stream_idle_timeout: Option<Duration>,
+        /// Surface refusals as `ChatEvent::Refusal` / `LlamaError::Refusal` instead of
+        /// normal content (default `false`).
+        detect_refusals: Option<bool>,
+        /// Case-insensitive prefixes that mark an answer as a refusal, for models that
+        /// don't send the `refusal` field, e.g. `"I'm sorry, but I can't"`.
+        refusal_patterns: Option<Vec<String>>,
+        /// Fails with `LlamaError::RequestTooLarge` before uploading a body larger than
+        /// this, e.g. one with several big base64 images.
+        max_request_bytes: Option<usize>,
+        /// Default for every request; `chat`/`chat_stream` can override it per call.
+        response_format: Option<ResponseFormat>,
+        /// Default: 3 attempts with exponential backoff. Use `RetryPolicy::disabled()` to
+        /// fail right away.
+        retry: Option<RetryPolicy>,
+        /// Added to the logits of these token ids on every request; a large negative bias
+        /// forbids a token. Look ids up with [`LlamaClient::tokenize`].
+        logit_bias: Option<HashMap<i32, f32>>,
+        /// Ask for the log-probability of every generated token (default `false`). Read
+        /// them with `ChatSession::last_logprobs` or as `ChatEvent::Logprobs`.
+        logprobs: Option<bool>,
+        /// Also return this many most likely alternatives per token; implies `logprobs`.
+        top_logprobs: Option<u8>,
+        /// Local copy of the server model's vocabulary, for `count_prompt_tokens`.
+        tokenizer: Option<Arc<Tokenizer>>,
+    ) -> LlamaResult<Self> {
+        let mut urls = vec![base_url.to_string()];
+        urls.extend(fallback_urls.unwrap_or_default());
+        let endpoints = Endpoints {
+            down_until: Mutex::new(vec![None; urls.len()]),
+            urls,
+            strategy: failover.unwrap_or_default(),
+            next: AtomicUsize::new(0),
+        };
+        let mut http = reqwest::Client::builder();
+        if let Some(timeout) = connect_timeout {
+            http = http.connect_timeout(timeout);
+        }
+        Ok(Self {
+            http: http.build()?,
+            endpoints: Arc::new(endpoints),
             config: LlamaConfig {
+                model,
                 temperature: temperature.unwrap_or(0.7),
                 top_p: top_p.unwrap_or(0.8),
+                top_k,
+                typical_p,
                 repetition_penalty: repetition_penalty.unwrap_or(1.0),
+                repeat_last_n,
                 presence_penalty: presence_penalty.unwrap_or(1.5),
+                emit_unknown_deltas: emit_unknown_deltas.unwrap_or(false),
+                cache_prompt: cache_prompt.unwrap_or(true),
+                seed,
+                request_timeout,
+                stream_idle_timeout,
+                detect_refusals: detect_refusals.unwrap_or(false),
+                refusal_patterns: refusal_patterns.unwrap_or_default(),
+                max_request_bytes,
+                response_format,
+                retry: retry.unwrap_or_default(),
+                logit_bias: logit_bias.unwrap_or_default(),
+                logprobs: top_logprobs.or_else(|| logprobs.unwrap_or(false).then_some(0)),
             },
+            tokenizer,
+        })
+    }
+
+    async fn post_json(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+        timeout: Option<Duration>,
+    ) -> LlamaResult<reqwest::Response> {
+        let body = serde_json::to_vec(body)?;
+        if let Some(limit) = self.config.max_request_bytes {
+            if body.len() > limit {
+                return Err(LlamaError::RequestTooLarge {
+                    estimated: body.len(),
+                    limit,
+                });
+            }
+        }
+        let retry = self.config.retry;
+        let mut attempt = 1;
+        loop {
+            match self.try_endpoints(path, Some(&body), timeout).await {
+                Err(e) if attempt < retry.max_attempts && is_transient(&e) => {
+                    let backoff = retry.backoff(attempt - 1);
+                    info!("Request to {path} failed ({e}), retrying in {backoff:?}");
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // POSTs `body`, or GETs without one, failing over to the next endpoint on connection
+    // errors, timeouts and 5xx responses.
+    async fn try_endpoints(
+        &self,
+        path: &str,
+        body: Option<&[u8]>,
+        timeout: Option<Duration>,
+    ) -> LlamaResult<reqwest::Response> {
+        let mut failures = Vec::new();
+        for index in self.endpoints.order() {
+            let url = format!("{}{path}", self.endpoints.urls[index]);
+            let mut request = body.map_or_else(
+                || self.http.get(&url),
+                |body| {
+                    self.http
+                        .post(&url)
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .body(body.to_vec())
+                },
+            );
+            if let Some(timeout) = timeout {
+                request = request.timeout(timeout);
+            }
+            let error = match request.send().await {
+                Ok(response) if response.status().is_server_error() => api_error(response).await,
+                Ok(response) => {
+                    self.endpoints.mark(index, true);
+                    if !response.status().is_success() {
+                        return Err(api_error(response).await);
+                    }
+                    return Ok(response);
+                }
+                Err(e) if e.is_timeout() => LlamaError::Timeout,
+                Err(e) if e.is_connect() => LlamaError::Http(e),
+                Err(e) => return Err(e.into()),
+            };
+            self.endpoints.mark(index, false);
+            failures.push((url, error));
+        }
+        if failures.len() == 1 {
+            return Err(failures.remove(0).1);
+        }
+        Err(LlamaError::AllEndpointsFailed(failures))
+    }
+
+    fn model_or_default(&self, model: String) -> String {
+        match &self.config.model {
+            Some(default) if model.is_empty() => default.clone(),
+            _ => model,
         }
     }
 
-    const fn build_request(
+    fn build_request(
         &self,
         model: String,
         messages: Vec<Message>,
         stream: bool,
+        overrides: &RequestOverrides,
     ) -> ChatRequest {
+        let model = self.model_or_default(model);
         ChatRequest {
             model,
             messages,
             stream,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
             top_p: self.config.top_p,
-            temperature: self.config.temperature,
+            top_k: self.config.top_k,
+            typical_p: self.config.typical_p,
+            temperature: overrides.temperature.unwrap_or(self.config.temperature),
             repetition_penalty: self.config.repetition_penalty,
+            repeat_last_n: self.config.repeat_last_n,
             presence_penalty: self.config.presence_penalty,
+            cache_prompt: self.config.cache_prompt,
+            seed: overrides.seed.or(self.config.seed),
+            grammar: overrides.grammar.clone(),
+            response_format: overrides
+                .response_format
+                .clone()
+                .or_else(|| self.config.response_format.clone()),
+            tools: overrides.tools.clone(),
+            logit_bias: self
+                .config
+                .logit_bias
+                .iter()
+                .map(|(&k, &v)| (k, v))
+                .collect(),
+            n: overrides.n,
+            logprobs: self.config.logprobs.map(|_| true),
+            top_logprobs: self.config.logprobs.filter(|&n| n > 0),
         }
     }
 
@@ -166,18 +881,131 @@ impl LlamaClient {
         model: String,
         messages: Vec<Message>,
     ) -> LlamaResult<ChatFullResponse> {
-        let req_body = self.build_request(model, messages, false);
-        let url = format!("{}/v1/chat/completions", self.base_url);
+        self.full_request_with(model, messages, &RequestOverrides::default())
+            .await
+    }
 
-        let response = self.http.post(url).json(&req_body).send().await?;
+    pub async fn full_request_with(
+        &self,
+        model: String,
+        messages: Vec<Message>,
+        overrides: &RequestOverrides,
+    ) -> LlamaResult<ChatFullResponse> {
+        let req_body = self.build_request(model, messages, false, overrides);
+        let response = self
+            .post_json(
+                "/v1/chat/completions",
+                &req_body,
+                self.config.request_timeout,
+            )
+            .await?;
+        Ok(response.json().await?)
+    }
 
+    pub async fn tokenize(&self, content: &str) -> LlamaResult<Vec<i32>> {
+        let response = self
+            .post_json(
+                "/tokenize",
+                &TokenizeRequest { content },
+                self.config.request_timeout,
+            )
+            .await?;
+        Ok(response.json::<TokenizeResponse>().await?.tokens)
+    }
+
+    // A single attempt against the preferred endpoint: no retries, no failover, and no
+    // marking endpoints down, for estimates that have a fallback of their own.
+    async fn tokenize_once(&self, content: &str) -> LlamaResult<Vec<i32>> {
+        let index = self.endpoints.order()[0];
+        let url = format!("{}/tokenize", self.endpoints.urls[index]);
+        let mut request = self.http.post(url).json(&TokenizeRequest { content });
+        if let Some(timeout) = self.config.request_timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await?;
         if !response.status().is_success() {
-            return Err(LlamaError::Api {
-                status: response.status(),
-                body: response.text().await.unwrap_or_default(),
-            });
+            return Err(api_error(response).await);
         }
-        Ok(response.json().await?)
+        Ok(response.json::<TokenizeResponse>().await?.tokens)
+    }
+
+    /// Estimates the prompt tokens `messages` take, without a request, using the client's
+    /// `tokenizer`, e.g. to drop old turns until a conversation fits the context.
+    ///
+    /// Only text is counted: image parts are skipped, though each costs the server
+    /// hundreds of tokens or more (see [`estimate_image_tokens`]). Tool calls and tool
+    /// definitions aren't counted either.
+    pub fn count_prompt_tokens(&self, messages: &[Message]) -> LlamaResult<usize> {
+        let tokenizer = self.tokenizer.as_ref().ok_or(LlamaError::NoTokenizer)?;
+        let texts: Vec<(&str, String)> = messages
+            .iter()
+            .map(|m| {
+                let text = match &m.content {
+                    MessageContent::Text(text) => text.clone(),
+                    MessageContent::Parts(parts) => parts
+                        .iter()
+                        .filter_map(|part| match part {
+                            MessagePart::Text { text } => Some(text.as_str()),
+                            MessagePart::ImageUrl { .. } => None,
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                };
+                (m.role.as_str(), text)
+            })
+            .collect();
+        let messages: Vec<(&str, &str)> = texts
+            .iter()
+            .map(|(role, text)| (*role, text.as_str()))
+            .collect();
+        tokenizer
+            .count_chat_tokens(&messages)
+            .map_err(|e| LlamaError::Tokenizer(e.to_string()))
+    }
+
+    /// `true` once the server has loaded its model and accepts requests; `false` while it's
+    /// still loading.
+    pub async fn health(&self) -> LlamaResult<bool> {
+        match self.get("/health").await {
+            Ok(_) => Ok(true),
+            Err(e) if is_loading(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Ids of the models the server offers.
+    pub async fn models(&self) -> LlamaResult<Vec<String>> {
+        let response = self.get("/v1/models").await?;
+        let models = response.json::<ModelsResponse>().await?;
+        Ok(models.data.into_iter().map(|m| m.id).collect())
+    }
+
+    // GET for status queries, failing over like other requests but never retried.
+    async fn get(&self, path: &str) -> LlamaResult<reqwest::Response> {
+        self.try_endpoints(path, None, self.config.request_timeout)
+            .await
+    }
+
+    /// One embedding per `input`, in the same order. The server must be started with
+    /// `--embeddings`.
+    pub async fn embeddings(
+        &self,
+        model: String,
+        input: Vec<String>,
+    ) -> LlamaResult<Vec<Vec<f32>>> {
+        let response = self
+            .post_json(
+                "/v1/embeddings",
+                &EmbeddingsRequest {
+                    model: self.model_or_default(model),
+                    input,
+                },
+                self.config.request_timeout,
+            )
+            .await?;
+        let mut data = response.json::<EmbeddingsResponse>().await?.data;
+        data.sort_by_key(|d| d.index);
+        Ok(data.into_iter().map(|d| d.embedding).collect())
     }
 
     pub async fn stream_request(
@@ -185,62 +1013,441 @@ impl LlamaClient {
         model: String,
         messages: Vec<Message>,
     ) -> LlamaResult<Pin<Box<dyn Stream<Item = LlamaResult<ChatEvent>> + Send>>> {
-        let req_body = self.build_request(model, messages, true);
-        let url = format!("{}/v1/chat/completions", self.base_url);
-        let response = self.http.post(url).json(&req_body).send().await?;
-        if !response.status().is_success() {
-            return Err(LlamaError::Api {
-                status: response.status(),
-                body: response.text().await.unwrap_or_default(),
-            });
-        }
+        self.stream_request_with(model, messages, &RequestOverrides::default())
+            .await
+    }
+
+    pub async fn stream_request_with(
+        &self,
+        model: String,
+        messages: Vec<Message>,
+        overrides: &RequestOverrides,
+    ) -> LlamaResult<Pin<Box<dyn Stream<Item = LlamaResult<ChatEvent>> + Send>>> {
+        let req_body = self.build_request(model, messages, true, overrides);
+        let response = self
+            .post_json("/v1/chat/completions", &req_body, None)
+            .await?;
         let stream_bytes = response
             .bytes_stream()
             .map_err(std::io::Error::other);
-        let reader = StreamReader::new(stream_bytes);
-        let mut lines = BufReader::new(reader).lines();
+        let reader = BufReader::new(StreamReader::new(stream_bytes));
+        let mut payloads = Box::pin(sse_data(reader, self.config.stream_idle_timeout));
+        let emit_unknown = self.config.emit_unknown_deltas;
+        let config = self.config.clone();
         Ok(Box::pin(try_stream! {
-            while let Some(line) = lines.next_line().await.map_err(LlamaError::Io)? {
-                let line = line.trim();
-                if line.is_empty() || line == "data: [DONE]" { continue; }
-                if let Some(data) = line.strip_prefix("data: ") {
-                    let chunk = serde_json::from_str::<ChatChunk>(data).map_err(LlamaError::Json)?;
-                    if let Some(choice) = chunk.choices.first() {
-                        if let Some(r) = &choice.delta.reasoning_content {
-                            yield ChatEvent::Reasoning(r.clone());
+            let mut content = String::new();
+            let mut finish_reason = None;
+            let mut tool_calls: Vec<WireToolCall> = Vec::new();
+            while let Some(data) = payloads.next().await {
+                let data = data?;
+                let chunk = serde_json::from_str::<ChatChunk>(&data).map_err(LlamaError::Json)?;
+                if let Some(usage) = chunk.usage {
+                    yield ChatEvent::Usage {
+                        prompt_tokens: usage.prompt_tokens,
+                        completion_tokens: usage.completion_tokens,
+                    };
+                }
+                if let Some(choice) = chunk.choices.into_iter().next() {
+                    if choice.finish_reason.is_some() {
+                        finish_reason = choice.finish_reason;
+                    }
+                    let mut delta = choice.delta;
+                    if let Some(r) = delta.reasoning_content {
+                        yield ChatEvent::Reasoning(r);
+                    }
+                    if let Some(c) = delta.content {
+                        if config.detect_refusals && !config.refusal_patterns.is_empty() {
+                            content.push_str(&c);
+                        }
+                        yield ChatEvent::Content(c);
+                    }
+                    if let Some(logprobs) = choice.logprobs.and_then(|l| l.content) {
+                        if !logprobs.is_empty() {
+                            yield ChatEvent::Logprobs(logprobs);
+                        }
+                    }
+                    for fragment in delta.tool_calls.unwrap_or_default() {
+                        merge_tool_call(&mut tool_calls, fragment);
+                    }
+                    match delta.refusal {
+                        Some(refusal) if config.detect_refusals => {
+                            yield ChatEvent::Refusal(refusal);
                         }
-                        if let Some(c) = &choice.delta.content {
-                            yield ChatEvent::Content(c.clone());
+                        Some(refusal) => {
+                            delta.extra.insert("refusal".into(), refusal.into());
                         }
+                        None => {}
                     }
+                    if emit_unknown {
+                        // `role` is sent on the first delta and carries no content.
+                        delta.extra.remove("role");
+                        delta.extra.retain(|_, v| !v.is_null());
+                        if !delta.extra.is_empty() {
+                            yield ChatEvent::Unknown(serde_json::Value::Object(delta.extra));
+                        }
+                    }
+                }
+            }
+            for call in tool_calls {
+                yield ChatEvent::ToolCall(call.into());
+            }
+            // Pattern matches can only be decided once the whole answer is in.
+            if config.detect_refusals && config.is_refusal(&content) {
+                yield ChatEvent::Refusal(content);
+            }
+            yield ChatEvent::Done { finish_reason };
+        }))
+    }
+}
+
+// The data of each server-sent event read from `reader`, up to the end of the body. A line
+// may take at most `idle_timeout` to arrive.
+fn sse_data<R: AsyncBufRead + Unpin + Send>(
+    reader: R,
+    idle_timeout: Option<Duration>,
+) -> impl Stream<Item = LlamaResult<String>> + Send {
+    try_stream! {
+        let mut lines = reader.lines();
+        let mut event = SseEvent::default();
+        loop {
+            let next_line = match idle_timeout {
+                Some(idle) => tokio::time::timeout(idle, lines.next_line())
+                    .await
+                    .map_err(|_| LlamaError::Timeout)?,
+                None => lines.next_line().await,
+            };
+            let data = match next_line.map_err(LlamaError::Io)? {
+                Some(line) => event.push_line(&line),
+                // Tolerate a last event that isn't followed by a blank line.
+                None => match event.finish() {
+                    Some(data) => Some(data),
+                    None => break,
+                },
+            };
+            let Some(data) = data else { continue };
+            // Some servers send it as `[done]` or with extra whitespace.
+            if data.trim().eq_ignore_ascii_case("[DONE]") {
+                continue;
+            }
+            yield data;
+        }
+    }
+}
+
+/// Assembles server-sent events from lines. `data:` fields accumulate (joined by `\n`)
+/// until a blank line ends the event; comments (`:...`) and other fields are ignored.
+#[derive(Default)]
+struct SseEvent {
+    data: Option<String>,
+}
+
+impl SseEvent {
+    // The event's data, once `line` completes it.
+    fn push_line(&mut self, line: &str) -> Option<String> {
+        // `lines()` leaves the `\r` of CRLF endings.
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            return self.finish();
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        if field == "data" {
+            // `data:x` and `data: x` are the same value.
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match &mut self.data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            }
+        }
+        None
+    }
+
+    const fn finish(&mut self) -> Option<String> {
+        self.data.take()
+    }
+}
+
+#[derive(Serialize)]
+struct TokenizeRequest<'a> {
+    content: &'a str,
+}
+
+#[derive(Deserialize)]
+struct TokenizeResponse {
+    tokens: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+struct ModelsResponse {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct EmbeddingsRequest {
+    model: String,
+    input: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+const CHARS_PER_TOKEN: usize = 4;
+const DEFAULT_MAX_ACCUMULATED_BYTES: usize = 64 * 1024 * 1024;
+
+pub struct ChatResponseStream<'a> {
+    inner: Pin<Box<dyn Stream<Item = LlamaResult<ChatEvent>> + Send>>,
+    session: &'a mut ChatSession,
+    accumulated_content: String,
+    accumulated_reasoning: String,
+    is_done: bool,
+    cancelled: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+    transcript: Option<TranscriptRecord>,
+    tool_calls: Vec<ToolCall>,
+    logprobs: Vec<Logprob>,
+    started: Instant,
+    first_token_at: Option<Instant>,
+    last_event_at: Option<Instant>,
+}
+
+impl ChatResponseStream<'_> {
+    /// Time from sending the request to the first content or reasoning piece, which is
+    /// mostly prefill (long for large images). `None` until that piece has arrived.
+    pub fn time_to_first_token(&self) -> Option<Duration> {
+        self.first_token_at.map(|at| at - self.started)
+    }
+
+    /// Time since the last event arrived (or since the request was sent), to detect stalls.
+    pub fn since_last_event(&self) -> Duration {
+        self.last_event_at.unwrap_or(self.started).elapsed()
+    }
+
+    // Records whatever content arrived so the history stays consistent, even when the
+    // stream was cancelled part-way. Without any answer the question is taken back out,
+    // so the next turn doesn't send two user messages in a row.
+    fn finish(&mut self) {
+        self.is_done = true;
+        self.session.last_reasoning =
+            (!self.accumulated_reasoning.is_empty()).then(|| self.accumulated_reasoning.clone());
+        self.session.last_logprobs = std::mem::take(&mut self.logprobs);
+        let record = self.transcript.take();
+        self.session
+            .write_transcript(record, &self.accumulated_content);
+        if self.is_empty() {
+            self.session.messages.pop();
+        } else {
+            let content = self.accumulated_content.clone();
+            let tool_calls = std::mem::take(&mut self.tool_calls);
+            self.session.push_assistant(content, tool_calls);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.accumulated_content.trim().is_empty() && self.tool_calls.is_empty()
+    }
+
+    pub async fn reasoning_token_estimate(&mut self) -> usize {
+        token_estimate(&self.session.client, &self.accumulated_reasoning).await
+    }
+
+    pub async fn content_token_estimate(&mut self) -> usize {
+        token_estimate(&self.session.client, &self.accumulated_content).await
+    }
+}
+
+// Prefer the server's tokenizer, fall back to a chars-per-token heuristic right away when
+// `/tokenize` isn't available (e.g. non llama-server backends).
+async fn token_estimate(client: &LlamaClient, text: &str) -> usize {
+    if text.is_empty() {
+        return 0;
+    }
+    client.tokenize_once(text).await.map_or_else(
+        |_| text.chars().count().div_ceil(CHARS_PER_TOKEN),
+        |tokens| tokens.len(),
+    )
+}
+
+// Qwen3-VL uses 16 px patches merged 2x2, so each image token covers 32x32 pixels.
+const IMAGE_TOKEN_EDGE: u32 = 32;
+// llama.cpp's limits for Qwen3-VL images, in tokens.
+const MIN_IMAGE_TOKENS: f64 = 8.0;
+const MAX_IMAGE_TOKENS: f64 = 4096.0;
+
+/// Roughly how many context tokens llama-server spends on a `width` x `height` image with
+/// a Qwen3-VL projector, to check a request fits before sending it (or downscale first).
+///
+/// Follows the projector's resize: both sides are rounded to a multiple of 32 pixels and
+/// the area is scaled into the 8..=4096 token range. Other models count differently.
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+pub fn estimate_image_tokens(width: u32, height: u32) -> usize {
+    let edge = f64::from(IMAGE_TOKEN_EDGE);
+    let (width, height) = (f64::from(width.max(1)), f64::from(height.max(1)));
+    let round = |side: f64| (side / edge).round().max(1.0);
+    let (mut columns, mut rows) = (round(width), round(height));
+    let area = (width / edge) * (height / edge);
+    if columns * rows > MAX_IMAGE_TOKENS {
+        let scale = (area / MAX_IMAGE_TOKENS).sqrt();
+        columns = (width / (scale * edge)).floor().max(1.0);
+        rows = (height / (scale * edge)).floor().max(1.0);
+    } else if columns * rows < MIN_IMAGE_TOKENS {
+        let scale = (MIN_IMAGE_TOKENS / area).sqrt();
+        columns = (width * scale / edge).ceil();
+        rows = (height * scale / edge).ceil();
+    }
+    (columns * rows) as usize
+}
+
+impl Stream for ChatResponseStream<'_> {
+    type Item = LlamaResult<ChatEvent>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_done {
+            return Poll::Ready(None);
+        }
+        if let Some(cancelled) = self.cancelled.as_mut() {
+            if cancelled.as_mut().poll(cx).is_ready() {
+                // Dropping the response body closes the connection, which stops generation
+                // on llama-server.
+                self.inner = Box::pin(futures_util::stream::empty());
+                self.finish();
+                return Poll::Ready(None);
+            }
+        }
+        let result = self.inner.poll_next_unpin(cx);
+        if let Poll::Ready(Some(event)) = &result {
+            let now = Instant::now();
+            self.last_event_at = Some(now);
+            if matches!(event, Ok(ChatEvent::Content(_) | ChatEvent::Reasoning(_))) {
+                self.first_token_at.get_or_insert(now);
+            }
+        }
+        match &result {
+            Poll::Ready(Some(Ok(ChatEvent::Content(c)))) => {
+                self.accumulated_content.push_str(c);
+            }
+            Poll::Ready(Some(Ok(ChatEvent::Reasoning(r)))) => {
+                self.accumulated_reasoning.push_str(r);
+            }
+            Poll::Ready(Some(Ok(ChatEvent::ToolCall(call)))) => {
+                self.tool_calls.push(call.clone());
+            }
+            Poll::Ready(Some(Ok(ChatEvent::Logprobs(logprobs)))) => {
+                self.logprobs.extend_from_slice(logprobs);
+            }
+            &Poll::Ready(Some(Ok(ChatEvent::Usage {
+                prompt_tokens,
+                completion_tokens,
+            }))) => {
+                self.session.last_usage = Some(Usage {
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens: prompt_tokens + completion_tokens,
+                });
+            }
+            Poll::Ready(None) => {
+                self.finish();
+                if self.is_empty() {
+                    return Poll::Ready(Some(Err(LlamaError::EmptyResponse)));
                 }
             }
-        }))
+            _ => {}
+        }
+        let limit = self.session.max_accumulated_bytes;
+        if self.accumulated_content.len() + self.accumulated_reasoning.len() > limit {
+            // Keeps what arrived, like a cancel, and stops generation on the server.
+            self.inner = Box::pin(futures_util::stream::empty());
+            self.finish();
+            return Poll::Ready(Some(Err(LlamaError::ResponseTooLarge { limit })));
+        }
+        result
     }
 }
 
-pub struct ChatResponseStream<'a> {
+/// A streamed answer that doesn't borrow its session, from
+/// [`ChatSession::chat_stream_owned`].
+///
+/// When it ends with an answer, the assistant message is sent to the receiver returned
+/// with it; dropping it early sends nothing.
+pub struct OwnedChatStream {
     inner: Pin<Box<dyn Stream<Item = LlamaResult<ChatEvent>> + Send>>,
-    session: &'a mut ChatSession,
-    accumulated_content: String,
+    content: String,
+    reasoning_len: usize,
+    tool_calls: Vec<ToolCall>,
+    max_accumulated_bytes: usize,
+    transcript: Option<(Arc<TranscriptLogger>, TranscriptRecord)>,
+    // Taken once the stream is done.
+    reply: Option<oneshot::Sender<Message>>,
 }
 
-impl Stream for ChatResponseStream<'_> {
+impl OwnedChatStream {
+    fn is_empty(&self) -> bool {
+        self.content.trim().is_empty() && self.tool_calls.is_empty()
+    }
+
+    fn finish(&mut self) {
+        if let Some((logger, record)) = self.transcript.take() {
+            logger.log_output(record, &self.content);
+        }
+        let Some(reply) = self.reply.take() else {
+            return;
+        };
+        if !self.is_empty() {
+            // The receiver may have been dropped; the answer was still streamed.
+            let _ = reply.send(Message {
+                role: "assistant".to_string(),
+                content: MessageContent::Text(std::mem::take(&mut self.content)),
+                tool_calls: std::mem::take(&mut self.tool_calls),
+                tool_call_id: None,
+            });
+        }
+    }
+}
+
+impl Stream for OwnedChatStream {
     type Item = LlamaResult<ChatEvent>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.reply.is_none() {
+            return Poll::Ready(None);
+        }
         let result = self.inner.poll_next_unpin(cx);
         match &result {
-            Poll::Ready(Some(Ok(ChatEvent::Content(c)))) => {
-                self.accumulated_content.push_str(c);
+            Poll::Ready(Some(Ok(ChatEvent::Content(c)))) => self.content.push_str(c),
+            Poll::Ready(Some(Ok(ChatEvent::Reasoning(r)))) => self.reasoning_len += r.len(),
+            Poll::Ready(Some(Ok(ChatEvent::ToolCall(call)))) => {
+                self.tool_calls.push(call.clone());
             }
             Poll::Ready(None) => {
-                let content = std::mem::take(&mut self.accumulated_content);
-                if !content.is_empty() {
-                    self.session.push_text("assistant", content);
+                let empty = self.is_empty();
+                self.finish();
+                if empty {
+                    return Poll::Ready(Some(Err(LlamaError::EmptyResponse)));
                 }
             }
             _ => {}
         }
+        let limit = self.max_accumulated_bytes;
+        if self.content.len() + self.reasoning_len > limit {
+            self.inner = Box::pin(futures_util::stream::empty());
+            self.finish();
+            return Poll::Ready(Some(Err(LlamaError::ResponseTooLarge { limit })));
+        }
         result
     }
 }
@@ -248,41 +1455,259 @@ impl Stream for ChatResponseStream<'_> {
 pub struct ChatSession {
     client: LlamaClient,
     model: String,
+    system_prompt: Option<String>,
     messages: Vec<Message>,
+    pinned_images: Vec<MessagePart>,
+    image_loader: Arc<dyn BoxedImageLoader>,
+    temp_schedule: Vec<f32>,
+    max_accumulated_bytes: usize,
+    last_seed: Option<u32>,
+    last_usage: Option<Usage>,
+    last_finish_reason: Option<String>,
+    last_reasoning: Option<String>,
+    last_logprobs: Vec<Logprob>,
+    last_timings: Option<Timings>,
+    tools: Vec<ToolDefinition>,
+    transcript: Option<Arc<TranscriptLogger>>,
+    max_image_edge: Option<u32>,
 }
 
 #[bon]
 impl ChatSession {
     #[builder(start_fn = with_client)]
-    pub fn new(#[builder(start_fn)] client: LlamaClient, model: Option<String>) -> Self {
-        Self {
+    pub fn new(
+        #[builder(start_fn)] client: LlamaClient,
+        /// Default: the client's `model`, or else the first model the server lists, looked
+        /// up on the first request.
+        model: Option<String>,
+        /// Sent as the first message of every conversation, and kept across `reset()`.
+        system_prompt: Option<String>,
+        #[builder(with = |loader: impl ImageLoader + 'static| Arc::new(loader) as Arc<dyn BoxedImageLoader>)]
+        image_loader: Option<Arc<dyn BoxedImageLoader>>,
+        /// Temperature per assistant turn: the first answer uses `temp_schedule[0]`, the
+        /// second `temp_schedule[1]`, and so on, holding the last value once the schedule
+        /// runs out. A `temperature` passed to `chat`/`chat_stream` wins over the schedule.
+        temp_schedule: Option<Vec<f32>>,
+        /// Upper bound on streamed content + reasoning before `chat_stream` gives up with
+        /// `LlamaError::ResponseTooLarge` (default 64 MiB).
+        max_accumulated_bytes: Option<usize>,
+        /// Appends a record of every completed `chat`/`chat_stream` call.
+        transcript: Option<Arc<TranscriptLogger>>,
+        /// Functions offered to the model on every request. Calls it makes show up in
+        /// `last_tool_calls` (or as `ChatEvent::ToolCall`); answer them with
+        /// `push_tool_result` before the next `chat`.
+        tools: Option<Vec<ToolDefinition>>,
+        /// Downscales larger images to fit in this many pixels per side before upload,
+        /// keeping the aspect ratio, to bound the image tokens each one costs.
+        max_image_edge: Option<u32>,
+    ) -> Self {
+        let model = model
+            .or_else(|| client.config.model.clone())
+            .unwrap_or_default();
+        let mut session = Self {
             client,
-            model: model.unwrap_or_default(),
+            model,
+            system_prompt,
             messages: Vec::new(),
+            pinned_images: Vec::new(),
+            image_loader: image_loader.unwrap_or_else(|| Arc::new(FsImageLoader::default())),
+            temp_schedule: temp_schedule.unwrap_or_default(),
+            max_accumulated_bytes: max_accumulated_bytes.unwrap_or(DEFAULT_MAX_ACCUMULATED_BYTES),
+            last_seed: None,
+            last_usage: None,
+            last_finish_reason: None,
+            last_reasoning: None,
+            last_logprobs: Vec::new(),
+            last_timings: None,
+            tools: tools.unwrap_or_default(),
+            transcript,
+            max_image_edge,
+        };
+        session.reset();
+        session
+    }
+
+    /// Seed reported by the server for the last `chat` call, if any. Pass it back via
+    /// `.seed(...)` to reproduce that answer.
+    pub const fn last_seed(&self) -> Option<u32> {
+        self.last_seed
+    }
+
+    /// Token usage the server reported for the last `chat` or completed `chat_stream` call.
+    pub const fn last_usage(&self) -> Option<Usage> {
+        self.last_usage
+    }
+
+    /// Why the last `chat` answer ended: `"stop"` for a natural end, `"length"` when it hit
+    /// the token limit. Streams report this through `ChatEvent::Done` instead.
+    pub fn last_finish_reason(&self) -> Option<&str> {
+        self.last_finish_reason.as_deref()
+    }
+
+    /// The reasoning (`reasoning_content`) of the last `chat` or completed `chat_stream`
+    /// answer, if the server split any off. It isn't kept in the history.
+    pub fn last_reasoning(&self) -> Option<&str> {
+        self.last_reasoning.as_deref()
+    }
+
+    /// Per-token logprobs of the last `chat` or completed `chat_stream` answer; empty
+    /// unless the client was built with `logprobs` or `top_logprobs`.
+    pub fn last_logprobs(&self) -> &[Logprob] {
+        &self.last_logprobs
+    }
+
+    /// Stable hash of the request body the current history would produce (model, messages
+    /// including base64 images, sampling config), for application-level response caches.
+    pub fn request_fingerprint(&self) -> LlamaResult<u64> {
+        let request = self.client.build_request(
+            self.model.clone(),
+            self.request_messages(),
+            false,
+            &self.request_overrides(None, None),
+        );
+        let body = serde_json::to_vec(&request)?;
+        Ok(fnv1a(&body))
+    }
+
+    // The builder's `model`, or else the first model the server lists.
+    async fn resolve_model(&mut self) -> LlamaResult<String> {
+        if self.model.is_empty() {
+            if let Some(model) = self.client.models().await?.into_iter().next() {
+                self.model = model;
+            }
+        }
+        Ok(self.model.clone())
+    }
+
+    fn request_overrides(&self, temperature: Option<f32>, seed: Option<u32>) -> RequestOverrides {
+        let turn = self
+            .messages
+            .iter()
+            .filter(|m| m.role == "assistant")
+            .count();
+        let scheduled = self
+            .temp_schedule
+            .get(turn)
+            .or_else(|| self.temp_schedule.last())
+            .copied();
+        RequestOverrides {
+            temperature: temperature.or(scheduled),
+            seed,
+            tools: self.tools.clone(),
+            ..RequestOverrides::default()
+        }
+    }
+
+    fn transcript_record(
+        &self,
+        prompt: &str,
+        images: &[impl AsRef<Path>],
+        image_keys: &[&str],
+        overrides: &RequestOverrides,
+    ) -> LlamaResult<Option<TranscriptRecord>> {
+        if self.transcript.is_none() {
+            return Ok(None);
+        }
+        let request = self
+            .client
+            .build_request(self.model.clone(), Vec::new(), false, overrides);
+        let mut parameters = serde_json::to_value(request)?;
+        if let Some(object) = parameters.as_object_mut() {
+            object.remove("messages");
+            object.remove("stream");
+        }
+        let images = images
+            .iter()
+            .map(|p| p.as_ref().display().to_string())
+            .chain(image_keys.iter().map(ToString::to_string))
+            .collect();
+        Ok(Some(TranscriptRecord::new(
+            "http", prompt, images, parameters,
+        )))
+    }
+
+    fn write_transcript(&self, record: Option<TranscriptRecord>, output: &str) {
+        if let (Some(logger), Some(record)) = (&self.transcript, record) {
+            logger.log_output(record, output);
+        }
+    }
+
+    /// Pins an image to the conversation: it is encoded once and sent as part of the first
+    /// user message of every request, instead of being attached to a single turn.
+    ///
+    /// Pins survive `reset()`, so a new conversation about the same image doesn't need to
+    /// re-read it; use `clear_pinned_images` to drop them.
+    pub async fn pin_image(&mut self, path: impl AsRef<Path>) -> LlamaResult<()> {
+        let bytes = fs::read(path).await?;
+        let part = self.image_part(&bytes, None)?;
+        self.pinned_images.push(part);
+        Ok(())
+    }
+
+    /// Like `pin_image`, but loads the image through the session's `ImageLoader`.
+    pub async fn pin_image_key(&mut self, key: &str) -> LlamaResult<()> {
+        let (bytes, mime_type) = self.image_loader.load_boxed(key).await?;
+        let part = self.image_part(&bytes, mime_type.as_deref())?;
+        self.pinned_images.push(part);
+        Ok(())
+    }
+
+    pub fn clear_pinned_images(&mut self) {
+        self.pinned_images.clear();
+    }
+
+    fn image_part(&self, bytes: &[u8], mime_type: Option<&str>) -> LlamaResult<MessagePart> {
+        let downscaled = match self.max_image_edge {
+            Some(max_edge) => downscale_encoded(bytes, max_edge)?,
+            None => None,
+        };
+        // A downscaled image is sniffed again, in case it had to be re-encoded as PNG.
+        downscaled.map_or_else(
+            || MessagePart::image(bytes, mime_type),
+            |bytes| MessagePart::image(&bytes, None),
+        )
+    }
+
+    // History as sent to the server, with pinned images added to the first user message.
+    fn request_messages(&self) -> Vec<Message> {
+        let mut messages = self.messages.clone();
+        if self.pinned_images.is_empty() {
+            return messages;
         }
+        if let Some(first) = messages.iter_mut().find(|m| m.role == "user") {
+            let mut parts =
+                match std::mem::replace(&mut first.content, MessageContent::Parts(Vec::new())) {
+                    MessageContent::Text(text) => vec![MessagePart::Text { text }],
+                    MessageContent::Parts(parts) => parts,
+                };
+            parts.extend(self.pinned_images.iter().cloned());
+            first.content = MessageContent::Parts(parts);
+        }
+        messages
     }
 
     async fn prepare_user_message(
         &mut self,
         prompt: &str,
         images: &[impl AsRef<Path> + Sync],
+        image_keys: &[&str],
     ) -> LlamaResult<()> {
         let mut parts = vec![MessagePart::Text {
             text: prompt.to_string(),
         }];
         for path in images {
             let bytes = fs::read(path).await?;
-            let mime_type = infer::get(&bytes).map_or("image/jpeg", |kind| kind.mime_type());
-            let b64 = general_purpose::STANDARD.encode(&bytes);
-            parts.push(MessagePart::ImageUrl {
-                image_url: ImageUrl {
-                    url: format!("data:{mime_type};base64,{b64}"),
-                },
-            });
+            parts.push(self.image_part(&bytes, None)?);
+        }
+        for key in image_keys {
+            let (bytes, mime_type) = self.image_loader.load_boxed(key).await?;
+            parts.push(self.image_part(&bytes, mime_type.as_deref())?);
         }
         self.messages.push(Message {
             role: "user".to_string(),
             content: MessageContent::Parts(parts),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
         });
         Ok(())
     }
@@ -291,56 +1716,386 @@ impl ChatSession {
         self.messages.push(Message {
             role: role.to_string(),
             content: MessageContent::Text(text),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        });
+    }
+
+    /// Adds a message with any role and content, e.g. an example image and its caption as
+    /// a few-shot user/assistant pair before the real question.
+    pub fn push_parts(&mut self, role: &str, parts: Vec<MessagePart>) {
+        self.messages.push(Message {
+            role: role.to_string(),
+            content: MessageContent::Parts(parts),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        });
+    }
+
+    /// Adds a complete message, e.g. the answer of a `chat_stream_owned` stream.
+    pub fn push_message(&mut self, message: Message) {
+        self.messages.push(message);
+    }
+
+    fn push_assistant(&mut self, text: String, tool_calls: Vec<ToolCall>) {
+        self.messages.push(Message {
+            role: "assistant".to_string(),
+            content: MessageContent::Text(text),
+            tool_calls,
+            tool_call_id: None,
+        });
+    }
+
+    /// Adds the result of a tool call to the history, for the model to use next turn.
+    pub fn push_tool_result(&mut self, tool_call_id: &str, result: String) {
+        self.messages.push(Message {
+            role: "tool".to_string(),
+            content: MessageContent::Text(result),
+            tool_calls: Vec::new(),
+            tool_call_id: Some(tool_call_id.to_string()),
         });
     }
 
+    /// Tool calls made in the latest assistant turn.
+    pub fn last_tool_calls(&self) -> &[ToolCall] {
+        self.messages
+            .last()
+            .filter(|m| m.role == "assistant")
+            .map_or(&[], |m| &m.tool_calls)
+    }
+
     #[builder]
     pub async fn chat(
         &mut self,
         #[builder(start_fn)] prompt: &str,
         images: Option<&[&Path]>,
+        image_keys: Option<&[&str]>,
+        temperature: Option<f32>,
+        seed: Option<u32>,
+        response_format: Option<ResponseFormat>,
+        /// Start of the answer, sent as a trailing assistant message the model continues.
+        /// The returned (and stored) answer includes it.
+        assistant_prefix: Option<&str>,
     ) -> LlamaResult<String> {
-        self.prepare_user_message(prompt, images.unwrap_or_default())
+        let model = self.resolve_model().await?;
+        let overrides = RequestOverrides {
+            response_format,
+            ..self.request_overrides(temperature, seed)
+        };
+        let (images, image_keys) = (images.unwrap_or_default(), image_keys.unwrap_or_default());
+        let record = self.transcript_record(prompt, images, image_keys, &overrides)?;
+        self.prepare_user_message(prompt, images, image_keys)
             .await?;
+        let mut messages = self.request_messages();
+        if let Some(prefix) = assistant_prefix {
+            messages.push(Message {
+                role: "assistant".to_string(),
+                content: MessageContent::Text(prefix.to_string()),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+            });
+        }
         let response = self
             .client
-            .full_request(self.model.clone(), self.messages.clone())
+            .full_request_with(model, messages, &overrides)
             .await?;
-        let content = response
-            .choices
-            .first()
-            .and_then(|c| c.message.content.clone())
+        self.last_seed = response.seed;
+        self.last_usage = response.usage;
+        self.last_timings = response.timings;
+        let choice = response.choices.into_iter().next();
+        self.last_finish_reason = choice.as_ref().and_then(|c| c.finish_reason.clone());
+        self.last_logprobs = choice
+            .as_ref()
+            .and_then(|c| c.logprobs.clone())
+            .and_then(|l| l.content)
             .unwrap_or_default();
-        self.push_text("assistant", content.clone());
+        let message = choice.map(|c| c.message);
+        self.last_reasoning = message.as_ref().and_then(|m| m.reasoning_content.clone());
+        let (content, refusal, tool_calls) =
+            message.map_or_else(Default::default, |m| (m.content, m.refusal, m.tool_calls));
+        let config = &self.client.config;
+        if config.detect_refusals {
+            let refusal = refusal.or_else(|| content.clone().filter(|c| config.is_refusal(c)));
+            if let Some(refusal) = refusal {
+                self.write_transcript(record, &refusal);
+                self.push_text("assistant", refusal.clone());
+                return Err(LlamaError::Refusal(refusal));
+            }
+        }
+        let content = content.unwrap_or_default();
+        if content.trim().is_empty() && tool_calls.is_empty() {
+            self.write_transcript(record, &content);
+            self.messages.pop();
+            return Err(LlamaError::EmptyResponse);
+        }
+        let content = assistant_prefix.unwrap_or_default().to_string() + &content;
+        self.write_transcript(record, &content);
+        self.push_assistant(content.clone(), tool_calls);
         Ok(content)
     }
 
+    /// Answers with exactly one of `labels` for the image, enforced by a grammar on the
+    /// server. The question and answer are not added to the history.
+    pub async fn classify(
+        &mut self,
+        image: impl AsRef<Path> + Sync,
+        labels: &[&str],
+    ) -> LlamaResult<String> {
+        if labels.is_empty() {
+            return Err(LlamaError::NoLabels);
+        }
+        let model = self.resolve_model().await?;
+        let prompt = classify_prompt(labels);
+        let overrides = RequestOverrides {
+            grammar: Some(choice_grammar(labels)),
+            ..self.request_overrides(None, None)
+        };
+        self.prepare_user_message(&prompt, &[image], &[]).await?;
+        let messages = self.request_messages();
+        self.messages.pop();
+        let response = self
+            .client
+            .full_request_with(model, messages, &overrides)
+            .await?;
+        let content = response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|c| c.message.content);
+        matching_label(content.as_deref(), labels)
+    }
+
+    /// `n` independent answers to `prompt`, e.g. to rerank them. Neither the question nor
+    /// the answers are added to the history. Empty answers are left out; if every one is
+    /// empty, this fails with [`LlamaError::EmptyResponse`] like `chat`.
+    #[builder]
+    pub async fn candidates(
+        &mut self,
+        #[builder(start_fn)] prompt: &str,
+        #[builder(start_fn)] n: u32,
+        images: Option<&[&Path]>,
+        image_keys: Option<&[&str]>,
+        temperature: Option<f32>,
+        seed: Option<u32>,
+    ) -> LlamaResult<Vec<String>> {
+        let model = self.resolve_model().await?;
+        let overrides = RequestOverrides {
+            n: Some(n),
+            ..self.request_overrides(temperature, seed)
+        };
+        let (images, image_keys) = (images.unwrap_or_default(), image_keys.unwrap_or_default());
+        self.prepare_user_message(prompt, images, image_keys)
+            .await?;
+        let messages = self.request_messages();
+        self.messages.pop();
+        let response = self
+            .client
+            .full_request_with(model, messages, &overrides)
+            .await?;
+        let answers: Vec<String> = response
+            .choices
+            .into_iter()
+            .filter_map(|c| c.message.content)
+            .filter(|content| !content.trim().is_empty())
+            .collect();
+        if answers.is_empty() {
+            return Err(LlamaError::EmptyResponse);
+        }
+        Ok(answers)
+    }
+
+    /// `chat`, also returning token counts and timings for the call.
+    #[builder]
+    pub async fn chat_with_metadata(
+        &mut self,
+        #[builder(start_fn)] prompt: &str,
+        images: Option<&[&Path]>,
+        image_keys: Option<&[&str]>,
+        temperature: Option<f32>,
+        seed: Option<u32>,
+        response_format: Option<ResponseFormat>,
+    ) -> LlamaResult<(String, RequestMetadata)> {
+        let start = Instant::now();
+        let content = self
+            .chat(prompt)
+            .maybe_images(images)
+            .maybe_image_keys(image_keys)
+            .maybe_temperature(temperature)
+            .maybe_seed(seed)
+            .maybe_response_format(response_format)
+            .call()
+            .await?;
+        let timings = self.last_timings;
+        let metadata = RequestMetadata {
+            prompt_tokens: self
+                .last_usage
+                .map(|u| u.prompt_tokens)
+                .or_else(|| timings.map(|t| t.prompt_n)),
+            completion_tokens: self
+                .last_usage
+                .map(|u| u.completion_tokens)
+                .or_else(|| timings.map(|t| t.predicted_n)),
+            tokens_per_sec: timings.map(|t| t.predicted_per_second),
+            prompt_tokens_per_sec: timings.map(|t| t.prompt_per_second),
+            generation_time: timings
+                .and_then(|t| Duration::try_from_secs_f64(t.predicted_ms / 1000.0).ok()),
+            elapsed: start.elapsed(),
+            finish_reason: self.last_finish_reason.clone(),
+        };
+        Ok((content, metadata))
+    }
+
     #[builder]
     pub async fn chat_stream<'a>(
         &'a mut self,
         #[builder(start_fn)] prompt: &str,
         images: Option<&[&Path]>,
+        image_keys: Option<&[&str]>,
+        temperature: Option<f32>,
+        seed: Option<u32>,
+        response_format: Option<ResponseFormat>,
+        /// Ends the stream promptly when cancelled, aborting the HTTP response. Content
+        /// received so far is still added to the history; without any, the question is
+        /// removed from it again.
+        cancel: Option<CancellationToken>,
     ) -> LlamaResult<ChatResponseStream<'a>> {
-        self.prepare_user_message(prompt, images.unwrap_or_default())
+        let model = self.resolve_model().await?;
+        let overrides = RequestOverrides {
+            response_format,
+            ..self.request_overrides(temperature, seed)
+        };
+        let (images, image_keys) = (images.unwrap_or_default(), image_keys.unwrap_or_default());
+        let transcript = self.transcript_record(prompt, images, image_keys, &overrides)?;
+        self.prepare_user_message(prompt, images, image_keys)
             .await?;
+        let started = Instant::now();
         let inner = self
             .client
-            .stream_request(self.model.clone(), self.messages.clone())
+            .stream_request_with(model, self.request_messages(), &overrides)
             .await?;
         Ok(ChatResponseStream {
             inner,
             session: self,
             accumulated_content: String::new(),
+            accumulated_reasoning: String::new(),
+            is_done: false,
+            cancelled: cancel.map(|token| Box::pin(token.cancelled_owned())),
+            transcript,
+            tool_calls: Vec::new(),
+            logprobs: Vec::new(),
+            started,
+            first_token_at: None,
+            last_event_at: None,
         })
     }
 
+    /// Like [`chat_stream`](Self::chat_stream), but the stream is `'static`, so it can be
+    /// moved into a task while the session stays usable. The question is added to the
+    /// history right away; the answer arrives on the receiver once the stream completes,
+    /// for [`push_message`](Self::push_message). Usage and timings aren't recorded.
+    #[builder]
+    pub async fn chat_stream_owned(
+        &mut self,
+        #[builder(start_fn)] prompt: &str,
+        images: Option<&[&Path]>,
+        image_keys: Option<&[&str]>,
+        temperature: Option<f32>,
+        seed: Option<u32>,
+        response_format: Option<ResponseFormat>,
+    ) -> LlamaResult<(OwnedChatStream, oneshot::Receiver<Message>)> {
+        let model = self.resolve_model().await?;
+        let overrides = RequestOverrides {
+            response_format,
+            ..self.request_overrides(temperature, seed)
+        };
+        let (images, image_keys) = (images.unwrap_or_default(), image_keys.unwrap_or_default());
+        let record = self.transcript_record(prompt, images, image_keys, &overrides)?;
+        self.prepare_user_message(prompt, images, image_keys)
+            .await?;
+        let inner = self
+            .client
+            .stream_request_with(model, self.request_messages(), &overrides)
+            .await?;
+        let (reply, receiver) = oneshot::channel();
+        let stream = OwnedChatStream {
+            inner,
+            content: String::new(),
+            reasoning_len: 0,
+            tool_calls: Vec::new(),
+            max_accumulated_bytes: self.max_accumulated_bytes,
+            transcript: self.transcript.clone().zip(record),
+            reply: Some(reply),
+        };
+        Ok((stream, receiver))
+    }
+
+    /// The conversation so far, system prompt included.
+    pub fn history(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// The conversation, to trim or edit before the next request.
+    pub const fn history_mut(&mut self) -> &mut Vec<Message> {
+        &mut self.messages
+    }
+
+    /// Keeps only the last `n` turns (a user message and everything up to the next one) and
+    /// the leading system messages, to stay under the context limit.
+    pub fn truncate_to_last_n_turns(&mut self, n: usize) {
+        let system = self
+            .messages
+            .iter()
+            .take_while(|m| m.role == "system")
+            .count();
+        let turn_starts: Vec<usize> = (system..self.messages.len())
+            .filter(|&i| self.messages[i].role == "user")
+            .collect();
+        let cut = match turn_starts.len().checked_sub(n) {
+            None | Some(0) => return,
+            Some(i) => turn_starts.get(i).copied().unwrap_or(self.messages.len()),
+        };
+        self.messages.drain(system..cut);
+    }
+
+    /// Removes the last assistant reply, and with `include_user` the user message it
+    /// answered, e.g. to ask again with other sampling settings. Returns `false`, changing
+    /// nothing, when the history doesn't end with an assistant message.
+    pub fn pop_last_turn(&mut self, include_user: bool) -> bool {
+        if self.messages.last().is_none_or(|m| m.role != "assistant") {
+            return false;
+        }
+        self.messages.pop();
+        if include_user && self.messages.last().is_some_and(|m| m.role == "user") {
+            self.messages.pop();
+        }
+        true
+    }
+
     pub fn reset(&mut self) {
         self.messages.clear();
+        if let Some(system_prompt) = self.system_prompt.clone() {
+            self.push_text("system", system_prompt);
+        }
+    }
+
+    /// Writes the conversation (system prompt included) as JSON, for `load_history`.
+    pub async fn save_history(&self, path: impl AsRef<Path>) -> LlamaResult<()> {
+        let json = serde_json::to_vec_pretty(&self.messages)?;
+        fs::write(path, json).await?;
+        Ok(())
+    }
+
+    /// Replaces the conversation with one written by `save_history`. The client, model and
+    /// other settings stay as they are.
+    pub async fn load_history(&mut self, path: impl AsRef<Path>) -> LlamaResult<()> {
+        let json = fs::read(path).await?;
+        self.messages = serde_json::from_slice(&json)?;
+        Ok(())
     }
 }
 
 pub async fn run() -> Result<()> {
-    let client = LlamaClient::with_base_url("http://localhost:8080").build();
+    let client = LlamaClient::with_base_url("http://localhost:8080").build()?;
     let mut session = ChatSession::with_client(client).build();
 
     let img_island = Path::new("assets/img/island.png");
@@ -413,3 +2168,505 @@ pub async fn run() -> Result<()> {
 
     Ok(())
 }
+
+// The label `classify` got back; the server may not enforce the grammar, so it's checked.
+fn matching_label(content: Option<&str>, labels: &[&str]) -> LlamaResult<String> {
+    let answer = content.map(str::trim).unwrap_or_default();
+    if answer.is_empty() {
+        return Err(LlamaError::EmptyResponse);
+    }
+    if !labels.contains(&answer) {
+        return Err(LlamaError::UnknownLabel(answer.to_string()));
+    }
+    Ok(answer.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::LoadedImage;
+    use std::io::Cursor;
+
+    const NO_PATHS: &[&Path] = &[];
+
+    // Stands in for an S3 client: objects live in memory under `s3://<bucket>/<key>`.
+    struct MockBucket {
+        bucket: &'static str,
+        objects: HashMap<String, Vec<u8>>,
+    }
+
+    impl ImageLoader for MockBucket {
+        async fn load(&self, key: &str) -> std::io::Result<LoadedImage> {
+            let not_found = || std::io::Error::new(std::io::ErrorKind::NotFound, key);
+            let object = key
+                .strip_prefix("s3://")
+                .and_then(|rest| rest.strip_prefix(self.bucket))
+                .and_then(|rest| rest.strip_prefix('/'))
+                .ok_or_else(not_found)?;
+            let bytes = self.objects.get(object).ok_or_else(not_found)?.clone();
+            Ok((bytes, Some("image/png".to_string())))
+        }
+    }
+
+    fn png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image::RgbImage::new(2, 2)
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn offline_session() -> ChatSession {
+        // Nothing in these tests sends a request.
+        let client = LlamaClient::with_base_url("http://127.0.0.1:9")
+            .model("test".to_string())
+            .build()
+            .unwrap();
+        let loader = MockBucket {
+            bucket: "photos",
+            objects: HashMap::from([("cat.png".to_string(), png())]),
+        };
+        ChatSession::with_client(client)
+            .image_loader(loader)
+            .build()
+    }
+
+    fn serialized_request(client: &LlamaClient) -> serde_json::Value {
+        let request = client.build_request(
+            String::new(),
+            Vec::new(),
+            false,
+            &RequestOverrides::default(),
+        );
+        serde_json::to_value(request).unwrap()
+    }
+
+    #[test]
+    fn fingerprint_ignores_logit_bias_order() {
+        let session = |logit_bias: HashMap<i32, f32>| {
+            let client = LlamaClient::with_base_url("http://127.0.0.1:9")
+                .model("test".to_string())
+                .logit_bias(logit_bias)
+                .build()
+                .unwrap();
+            ChatSession::with_client(client).build()
+        };
+        // Each map gets its own random hasher, so their iteration orders differ.
+        let forward: HashMap<i32, f32> = (0..16).map(|id| (id, -1.0)).collect();
+        let backward: HashMap<i32, f32> = (0..16).rev().map(|id| (id, -1.0)).collect();
+        assert_eq!(
+            session(forward).request_fingerprint().unwrap(),
+            session(backward).request_fingerprint().unwrap()
+        );
+    }
+
+    #[test]
+    fn classify_rejects_answers_outside_the_labels() {
+        let labels = ["cat", "dog"];
+        assert_eq!(matching_label(Some(" dog\n"), &labels).unwrap(), "dog");
+        assert!(matches!(
+            matching_label(Some("bird"), &labels),
+            Err(LlamaError::UnknownLabel(answer)) if answer == "bird"
+        ));
+        assert!(matches!(
+            matching_label(Some("  "), &labels),
+            Err(LlamaError::EmptyResponse)
+        ));
+        assert!(matches!(
+            matching_label(None, &labels),
+            Err(LlamaError::EmptyResponse)
+        ));
+    }
+
+    #[tokio::test]
+    async fn classify_needs_labels() {
+        let mut session = offline_session();
+        let result = session.classify("cat.png", &[]).await;
+        assert!(matches!(result, Err(LlamaError::NoLabels)));
+        assert!(session.history().is_empty());
+    }
+
+    #[tokio::test]
+    async fn token_estimate_falls_back_without_retrying() {
+        // Nothing listens on port 9, and retrying would wait ten seconds.
+        let client = LlamaClient::with_base_url("http://127.0.0.1:9")
+            .retry(RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_secs(10),
+                max_backoff: Duration::from_secs(10),
+            })
+            .build()
+            .unwrap();
+        let estimate = tokio::time::timeout(
+            Duration::from_secs(5),
+            token_estimate(&client, "twelve chars"),
+        )
+        .await
+        .expect("estimate waited for retries");
+        assert_eq!(estimate, "twelve chars".len().div_ceil(CHARS_PER_TOKEN));
+    }
+
+    #[tokio::test]
+    async fn timeouts_are_retried() {
+        // Accepts connections but never answers, so every attempt times out.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let _socket = socket;
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+        let client = LlamaClient::with_base_url(&url)
+            .request_timeout(Duration::from_millis(100))
+            .retry(RetryPolicy {
+                max_attempts: 2,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(10),
+            })
+            .build()
+            .unwrap();
+        assert!(matches!(
+            client.tokenize("hi").await,
+            Err(LlamaError::Timeout)
+        ));
+        assert_eq!(accepted.load(Ordering::Relaxed), 2);
+    }
+
+    // Serves `body` as JSON to every request, one connection at a time.
+    async fn json_server(body: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = socket.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn status_queries_fail_over() {
+        let fallback = json_server(r#"{"data": [{"id": "qwen"}]}"#).await;
+        // Nothing listens on port 9.
+        let client = LlamaClient::with_base_url("http://127.0.0.1:9")
+            .fallback_urls(vec![fallback])
+            .build()
+            .unwrap();
+        assert_eq!(client.models().await.unwrap(), ["qwen"]);
+        assert!(client.health().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn candidates_skip_empty_answers() {
+        let server = json_server(
+            r#"{
+                "choices": [
+                    { "index": 0, "message": { "role": "assistant", "content": null } },
+                    { "index": 1, "message": { "role": "assistant", "content": "A cat." } },
+                    { "index": 2, "message": { "role": "assistant", "content": " " } }
+                ],
+                "created": 1700000000,
+                "model": "qwen3-vl"
+            }"#,
+        )
+        .await;
+        let client = LlamaClient::with_base_url(&server)
+            .model("test".to_string())
+            .build()
+            .unwrap();
+        let mut session = ChatSession::with_client(client).build();
+        let answers = session.candidates("What is it?", 3).call().await.unwrap();
+        assert_eq!(answers, ["A cat."]);
+        assert!(session.history().is_empty());
+    }
+
+    #[tokio::test]
+    async fn candidates_without_answers_are_empty_responses() {
+        let server = json_server(
+            r#"{
+                "choices": [{ "index": 0, "message": { "role": "assistant", "content": null } }],
+                "created": 1700000000,
+                "model": "qwen3-vl"
+            }"#,
+        )
+        .await;
+        let client = LlamaClient::with_base_url(&server)
+            .model("test".to_string())
+            .build()
+            .unwrap();
+        let mut session = ChatSession::with_client(client).build();
+        let result = session.candidates("What is it?", 2).call().await;
+        assert!(matches!(result, Err(LlamaError::EmptyResponse)));
+    }
+
+    #[test]
+    fn cache_prompt_is_sent() {
+        let client = LlamaClient::with_base_url("http://127.0.0.1:9")
+            .build()
+            .unwrap();
+        assert_eq!(serialized_request(&client)["cache_prompt"], true);
+
+        let client = LlamaClient::with_base_url("http://127.0.0.1:9")
+            .cache_prompt(false)
+            .build()
+            .unwrap();
+        assert_eq!(serialized_request(&client)["cache_prompt"], false);
+    }
+
+    const FULL_RESPONSE: &str = r#"{
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": "A cat." },
+            "finish_reason": "stop"
+        }],
+        "created": 1700000000,
+        "model": "qwen3-vl",
+        "usage": { "prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15 }
+    }"#;
+
+    #[test]
+    fn full_response_exposes_seed() {
+        let mut value: serde_json::Value = serde_json::from_str(FULL_RESPONSE).unwrap();
+        value["seed"] = 3_141_592.into();
+        let response: ChatFullResponse = serde_json::from_value(value).unwrap();
+        assert_eq!(response.seed, Some(3_141_592));
+        assert_eq!(
+            response.choices[0].message.content.as_deref(),
+            Some("A cat.")
+        );
+    }
+
+    #[test]
+    fn full_response_without_seed() {
+        let response: ChatFullResponse = serde_json::from_str(FULL_RESPONSE).unwrap();
+        assert_eq!(response.seed, None);
+        assert_eq!(response.usage.unwrap().total_tokens, 15);
+    }
+
+    // A stream over `events` for a question already in `session`'s history.
+    fn response_stream(
+        session: &mut ChatSession,
+        events: Vec<LlamaResult<ChatEvent>>,
+        cancel: Option<CancellationToken>,
+    ) -> ChatResponseStream<'_> {
+        session.push_text("user", "Describe the image.".to_string());
+        ChatResponseStream {
+            inner: Box::pin(
+                futures_util::stream::iter(events).chain(futures_util::stream::pending()),
+            ),
+            session,
+            accumulated_content: String::new(),
+            accumulated_reasoning: String::new(),
+            is_done: false,
+            cancelled: cancel.map(|token| Box::pin(token.cancelled_owned())),
+            transcript: None,
+            tool_calls: Vec::new(),
+            logprobs: Vec::new(),
+            started: Instant::now(),
+            first_token_at: None,
+            last_event_at: None,
+        }
+    }
+
+    fn roles(session: &ChatSession) -> Vec<&str> {
+        session.history().iter().map(|m| m.role.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn cancel_before_content_removes_question() {
+        let mut session = offline_session();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let mut stream = response_stream(&mut session, Vec::new(), Some(cancel));
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        assert!(roles(&session).is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancel_after_content_keeps_partial_answer() {
+        let mut session = offline_session();
+        let cancel = CancellationToken::new();
+        let events = vec![Ok(ChatEvent::Content("A beach".to_string()))];
+        let mut stream = response_stream(&mut session, events, Some(cancel.clone()));
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(ChatEvent::Content(_)))
+        ));
+        cancel.cancel();
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        assert_eq!(roles(&session), ["user", "assistant"]);
+    }
+
+    #[tokio::test]
+    async fn oversized_answer_is_kept_in_history() {
+        let mut session = offline_session();
+        session.max_accumulated_bytes = 8;
+        let events = vec![Ok(ChatEvent::Content("A long answer".to_string()))];
+        let mut stream = response_stream(&mut session, events, None);
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(LlamaError::ResponseTooLarge { limit: 8 }))
+        ));
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        assert_eq!(roles(&session), ["user", "assistant"]);
+    }
+
+    #[tokio::test]
+    async fn oversized_owned_answer_is_still_sent() {
+        let (reply, received) = oneshot::channel();
+        let events = vec![Ok(ChatEvent::Content("A long answer".to_string()))];
+        let mut stream = OwnedChatStream {
+            inner: Box::pin(
+                futures_util::stream::iter(events).chain(futures_util::stream::pending()),
+            ),
+            content: String::new(),
+            reasoning_len: 0,
+            tool_calls: Vec::new(),
+            max_accumulated_bytes: 8,
+            transcript: None,
+            reply: Some(reply),
+        };
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(LlamaError::ResponseTooLarge { limit: 8 }))
+        ));
+        assert!(stream.next().await.is_none());
+        let message = received.await.unwrap();
+        assert!(matches!(message.content, MessageContent::Text(text) if text == "A long answer"));
+    }
+
+    #[tokio::test]
+    async fn custom_loader_supplies_images() {
+        let mut session = offline_session();
+        session.pin_image_key("s3://photos/cat.png").await.unwrap();
+        session
+            .prepare_user_message("What is this?", NO_PATHS, &["s3://photos/cat.png"])
+            .await
+            .unwrap();
+        let messages = session.request_messages();
+        let MessageContent::Parts(parts) = &messages.last().unwrap().content else {
+            panic!("expected a multipart user message");
+        };
+        let images = parts
+            .iter()
+            .filter(|part| {
+                matches!(part, MessagePart::ImageUrl { image_url }
+                    if image_url.url.starts_with("data:image/png;base64,"))
+            })
+            .count();
+        // The turn's own image plus the pinned one.
+        assert_eq!(images, 2);
+    }
+
+    #[tokio::test]
+    async fn custom_loader_errors_surface() {
+        let mut session = offline_session();
+        let error = session
+            .pin_image_key("s3://other/cat.png")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, LlamaError::Io(e) if e.kind() == std::io::ErrorKind::NotFound));
+    }
+
+    // Feeds `lines` to a fresh parser, returning every completed event's data.
+    fn sse_events(lines: &[&str]) -> Vec<String> {
+        let mut event = SseEvent::default();
+        let mut events: Vec<String> = lines
+            .iter()
+            .filter_map(|line| event.push_line(line))
+            .collect();
+        events.extend(event.finish());
+        events
+    }
+
+    // Reads `chunks` as a byte stream split at exactly those boundaries.
+    async fn sse_events_from_chunks(chunks: &[&'static str]) -> Vec<String> {
+        let bytes = futures_util::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok::<_, std::io::Error>(chunk.as_bytes())),
+        );
+        sse_data(BufReader::new(StreamReader::new(bytes)), None)
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sse_event_split_across_reads() {
+        let chunks = ["da", "ta: {\"a\"", ":1}\r", "\n\r\nda", "ta: 2\n", "\n"];
+        assert_eq!(sse_events_from_chunks(&chunks).await, ["{\"a\":1}", "2"]);
+    }
+
+    #[tokio::test]
+    async fn sse_skips_done_markers() {
+        let chunks = [
+            "data: 1\n\n",
+            "data: [DONE]\n\n",
+            "data: [done]\n\n",
+            "data:  [DONE] \r\n\r\n",
+            "data:[DONE]\n\n",
+            "data: 2\n\n",
+            "data: [DONE]",
+        ];
+        assert_eq!(sse_events_from_chunks(&chunks).await, ["1", "2"]);
+    }
+
+    #[test]
+    fn sse_event_spans_several_lines() {
+        let mut event = SseEvent::default();
+        assert_eq!(event.push_line("data: {\"a\":"), None);
+        assert_eq!(event.push_line("data: 1}"), None);
+        assert_eq!(event.push_line(""), Some("{\"a\":\n1}".to_string()));
+        assert_eq!(event.finish(), None);
+    }
+
+    #[test]
+    fn sse_data_space_is_optional() {
+        assert_eq!(sse_events(&["data: x", "", "data:y", ""]), ["x", "y"]);
+        // Only one space is part of the framing.
+        assert_eq!(sse_events(&["data:  z", ""]), [" z"]);
+    }
+
+    #[test]
+    fn sse_ignores_comments_and_unknown_fields() {
+        let lines = [
+            ": keep-alive",
+            "event: message",
+            "id: 7",
+            "retry: 100",
+            "data: x",
+            "",
+        ];
+        assert_eq!(sse_events(&lines), ["x"]);
+        // A comment alone doesn't make an event.
+        assert!(sse_events(&[": ping", ""]).is_empty());
+    }
+
+    #[test]
+    fn sse_handles_crlf_endings() {
+        assert_eq!(sse_events(&["data: x\r", "data: y\r", "\r"]), ["x\ny"]);
+    }
+
+    #[test]
+    fn sse_keeps_last_event_without_blank_line() {
+        assert_eq!(sse_events(&["data: x", "", "data: y"]), ["x", "y"]);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::bindings::{MultimodalModel, SamplingParams, Session};
+use color_eyre::eyre::{eyre, Result};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const CAPTION_PROMPT: &str = "Caption this image in one paragraph. Respond with the caption only.";
+
+#[derive(Debug, Clone)]
+pub struct BenchCase {
+    pub prompt: String,
+    pub images: Vec<PathBuf>,
+}
+
+/// What [`MultimodalModel::benchmark`] runs: every case `iterations` times, each in a
+/// freshly reset session.
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub cases: Vec<BenchCase>,
+    pub iterations: usize,
+    /// Caps each answer so runs take a comparable amount of time.
+    pub max_new_tokens: Option<usize>,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        let cases = ["island", "farm", "torus"]
+            .into_iter()
+            .map(|name| BenchCase {
+                prompt: CAPTION_PROMPT.to_string(),
+                images: vec![PathBuf::from(format!("assets/img/{name}.png"))],
+            })
+            .collect();
+        Self {
+            cases,
+            iterations: 3,
+            max_new_tokens: Some(128),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RunTiming {
+    prefill: Duration,
+    first_token: Duration,
+    tokens: usize,
+    generation: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BenchStats {
+    pub runs: usize,
+    pub prefill_median: Duration,
+    pub prefill_p95: Duration,
+    /// From the start of the call to the first generated piece, prefill included.
+    pub first_token_median: Duration,
+    pub first_token_p95: Duration,
+    /// Generated tokens per second after the first token, over all runs.
+    pub tokens_per_sec: f64,
+}
+
+impl BenchStats {
+    fn from_runs(runs: &[RunTiming]) -> Self {
+        if runs.is_empty() {
+            return Self::default();
+        }
+        let prefill: Vec<Duration> = runs.iter().map(|r| r.prefill).collect();
+        let first_token: Vec<Duration> = runs.iter().map(|r| r.first_token).collect();
+        let tokens: usize = runs.iter().map(|r| r.tokens.saturating_sub(1)).sum();
+        let generation: Duration = runs.iter().map(|r| r.generation).sum();
+        #[allow(clippy::cast_precision_loss)]
+        let tokens_per_sec = if generation.is_zero() {
+            0.0
+        } else {
+            tokens as f64 / generation.as_secs_f64()
+        };
+        Self {
+            runs: runs.len(),
+            prefill_median: percentile(prefill.clone(), 0.5),
+            prefill_p95: percentile(prefill, 0.95),
+            first_token_median: percentile(first_token.clone(), 0.5),
+            first_token_p95: percentile(first_token, 0.95),
+            tokens_per_sec,
+        }
+    }
+}
+
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn percentile(mut values: Vec<Duration>, p: f64) -> Duration {
+    values.sort();
+    let index = ((values.len() - 1) as f64 * p).round() as usize;
+    values[index]
+}
+
+impl fmt::Display for BenchStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  runs:            {}", self.runs)?;
+        writeln!(
+            f,
+            "  prefill:         median {:?}, p95 {:?}",
+            self.prefill_median, self.prefill_p95
+        )?;
+        writeln!(
+            f,
+            "  first token:     median {:?}, p95 {:?}",
+            self.first_token_median, self.first_token_p95
+        )?;
+        write!(f, "  generation:      {:.1} tokens/s", self.tokens_per_sec)
+    }
+}
+
+/// `cold` covers only the very first run, before anything was warmed up (graph
+/// allocation, GPU kernels, caches); `warm` covers every run after it.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchReport {
+    pub cold: BenchStats,
+    pub warm: BenchStats,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Cold (first run):")?;
+        writeln!(f, "{}", self.cold)?;
+        writeln!(f, "Warm:")?;
+        write!(f, "{}", self.warm)
+    }
+}
+
+impl MultimodalModel {
+    /// Runs `config` in a dedicated session and reports latency and throughput, so a
+    /// model/hardware combination can be characterized with one call.
+    pub fn benchmark(&self, config: &BenchConfig) -> Result<BenchReport> {
+        if config.cases.is_empty() || config.iterations == 0 {
+            return Err(eyre!("Benchmark needs at least one case and one iteration"));
+        }
+        // Greedy sampling keeps answer lengths stable between runs.
+        let mut session = Session::with_model(self)
+            .sampling(SamplingParams::default())
+            .maybe_max_new_tokens(config.max_new_tokens)
+            .build()?;
+        let mut runs = Vec::new();
+        for _ in 0..config.iterations {
+            for case in &config.cases {
+                session.reset();
+                runs.push(time_run(&mut session, case)?);
+            }
+        }
+        session.reset();
+        Ok(BenchReport {
+            cold: BenchStats::from_runs(&runs[..1]),
+            warm: BenchStats::from_runs(&runs[1..]),
+        })
+    }
+}
+
+/// Median prefill time of a text-only turn through both prefill paths, from
+/// [`MultimodalModel::benchmark_text_prefill`].
+#[derive(Debug, Clone, Copy)]
+pub struct TextPrefillReport {
+    pub runs: usize,
+    /// Tokenized and decoded directly, as sessions do for turns without images.
+    pub direct_median: Duration,
+    /// Through mtmd's chunked evaluation, as turns with images are.
+    pub mtmd_median: Duration,
+}
+
+impl TextPrefillReport {
+    /// How many times faster the direct path is.
+    pub fn speedup(&self) -> f64 {
+        self.mtmd_median.as_secs_f64() / self.direct_median.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for TextPrefillReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  runs:            {}", self.runs)?;
+        writeln!(f, "  direct:          median {:?}", self.direct_median)?;
+        writeln!(f, "  mtmd:            median {:?}", self.mtmd_median)?;
+        write!(f, "  speedup:         {:.2}x", self.speedup())
+    }
+}
+
+impl MultimodalModel {
+    /// Prefills `prompt` (without images) `iterations` times through each path, alternating
+    /// them, after one warm-up run of each.
+    pub fn benchmark_text_prefill(
+        &self,
+        prompt: &str,
+        iterations: usize,
+    ) -> Result<TextPrefillReport> {
+        if iterations == 0 {
+            return Err(eyre!("Benchmark needs at least one iteration"));
+        }
+        let mut session = self.new_session()?;
+        session.time_text_prefill(prompt, false)?;
+        session.time_text_prefill(prompt, true)?;
+        let mut direct = Vec::new();
+        let mut mtmd = Vec::new();
+        for _ in 0..iterations {
+            direct.push(session.time_text_prefill(prompt, false)?);
+            mtmd.push(session.time_text_prefill(prompt, true)?);
+        }
+        Ok(TextPrefillReport {
+            runs: iterations,
+            direct_median: percentile(direct, 0.5),
+            mtmd_median: percentile(mtmd, 0.5),
+        })
+    }
+}
+
+fn time_run(session: &mut Session<'_>, case: &BenchCase) -> Result<RunTiming> {
+    let start = Instant::now();
+    let stream = session.stream_chat(&case.prompt, &case.images)?;
+    let prefill = start.elapsed();
+    let mut first_token = None;
+    let mut tokens = 0;
+    for piece in stream {
+        piece?;
+        first_token.get_or_insert_with(|| start.elapsed());
+        tokens += 1;
+    }
+    let first_token = first_token.unwrap_or_else(|| start.elapsed());
+    Ok(RunTiming {
+        prefill,
+        first_token,
+        tokens,
+        generation: start.elapsed().saturating_sub(first_token),
+    })
+}
@@ -1,143 +1,1986 @@
+use crate::bench::BenchConfig;
+use crate::grammar::{choice_grammar, classify_prompt, json_schema_grammar};
+use crate::images::{downscale, normalize, ImageLoader, ImageSource};
+use crate::transcript::{TranscriptLogger, TranscriptRecord};
+use bon::bon;
 use color_eyre::eyre::{eyre, Context, Result};
-use gag::Gag;
+use futures_util::{Stream, TryStreamExt};
+use gag::{Gag, Redirect};
 use llama_cpp_2::context::params::LlamaContextParams;
 use llama_cpp_2::context::LlamaContext;
 use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::params::LlamaModelParams;
-use llama_cpp_2::model::{LlamaChatMessage, LlamaModel, Special};
-use llama_cpp_2::mtmd::{MtmdBitmap, MtmdContext, MtmdContextParams, MtmdInputText};
+use llama_cpp_2::model::{AddBos, LlamaChatMessage, LlamaChatTemplate, LlamaModel, Special};
+use llama_cpp_2::mtmd::{
+    MtmdBitmap, MtmdContext, MtmdContextParams, MtmdInputChunkType, MtmdInputText,
+};
 use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use llama_cpp_2::token::logit_bias::LlamaLogitBias;
+use llama_cpp_2::token::LlamaToken;
 use llama_cpp_2::{mtmd, send_logs_to_tracing, LogOptions};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::CString;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::iter::FusedIterator;
 use std::num::NonZeroU32;
-use std::path::Path;
-use std::time::Instant;
-use tracing::info;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self as std_mpsc, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::task::Poll;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
 
 // todo:
-// * make the constants arguments for the Model struct via `bon`
 // * test speed of this compared to calling llama-server via rust
-const SHOW_LLAMA_LOGS: bool = false;
 const MODEL_PATH: &str = "assets/qwen3vl/Qwen3VL-4B-Instruct-Q4_K_M.gguf";
 const MMPROJ_PATH: &str = "assets/qwen3vl/mmproj-Qwen3VL-4B-Instruct-Q8_0.gguf";
 const GPU_LAYERS: u32 = 99;
 const CTX_SIZE: u32 = 4096;
+const N_THREADS: i32 = 8;
+const CAPTION_PROMPT: &str = "Caption this image in one paragraph. Respond with the caption only.";
+const OCR_PROMPT: &str =
+    "Transcribe all text in this image exactly, preserving line breaks. Respond with the text only.";
 
+/// Typed failures from the local backend. They are returned inside the `eyre::Report`, so
+/// match on them with `report.downcast_ref::<BindingsError>()`.
+#[derive(Error, Debug)]
+pub enum BindingsError {
+    #[error("Context is full ({n_past}/{ctx_size} positions used)")]
+    ContextFull { n_past: i32, ctx_size: u32 },
+    /// The prompt (images included) doesn't fit in the context; checked before it is
+    /// evaluated.
+    #[error("Prompt needs {needed} context positions, but only {available} are free")]
+    ContextOverflow { needed: usize, available: usize },
+    #[error("Loading the model took longer than {0:?}")]
+    LoadTimeout(Duration),
+    /// The prompt places images with media markers, but not one per image.
+    #[error("Prompt has {markers} media markers for {images} images")]
+    MarkerMismatch { markers: usize, images: usize },
+    #[error("Invalid sampler chain: {0}")]
+    InvalidSamplerChain(&'static str),
+}
+
+// llama.cpp can only be initialized once per process, so every model shares one backend,
+// which lives until the process exits.
+static BACKEND: OnceLock<Result<LlamaBackend, String>> = OnceLock::new();
+
+fn backend() -> Result<&'static LlamaBackend> {
+    BACKEND
+        .get_or_init(|| LlamaBackend::init().map_err(|e| e.to_string()))
+        .as_ref()
+        .map_err(|e| eyre!("Failed to init backend: {e}"))
+}
+
+/// Where llama.cpp's own output goes while a model loads.
+#[derive(Debug, Clone, Default)]
+pub enum LlamaLogs {
+    /// Discard everything, including errors (the default).
+    #[default]
+    Silent,
+    /// Forward llama.cpp logs to `tracing`, at the matching level.
+    Tracing,
+    /// Append everything llama.cpp prints to stdout/stderr to this file.
+    File(PathBuf),
+}
+
+/// Whether contexts use Flash Attention. A `bool` converts to `Enabled`/`Disabled`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlashAttention {
+    #[default]
+    Enabled,
+    Disabled,
+    /// Let llama.cpp decide, based on what the device supports.
+    Auto,
+}
+
+impl FlashAttention {
+    const fn policy(self) -> llama_cpp_sys_2::llama_flash_attn_type {
+        match self {
+            Self::Enabled => llama_cpp_sys_2::LLAMA_FLASH_ATTN_TYPE_ENABLED,
+            Self::Disabled => llama_cpp_sys_2::LLAMA_FLASH_ATTN_TYPE_DISABLED,
+            Self::Auto => llama_cpp_sys_2::LLAMA_FLASH_ATTN_TYPE_AUTO,
+        }
+    }
+}
+
+impl From<bool> for FlashAttention {
+    fn from(enabled: bool) -> Self {
+        if enabled {
+            Self::Enabled
+        } else {
+            Self::Disabled
+        }
+    }
+}
+
+/// The loaded text model plus its vision projector.
+///
+/// Both are loaded by [`MultimodalModel::load`] (or the builder's `load`), so a bad path or a projector that doesn't
+/// match the text model fails there rather than on the first `new_session`.
+///
+/// Any number of sessions can be created from one model, also on different threads (share
+/// it with `Arc` or `thread::scope`); each has its own context and KV cache. The weights
+/// are only read, so generation runs in parallel. The projector (`MtmdContext`) keeps
+/// encode buffers internally, so prompt and image evaluation is serialized across
+/// sessions by a lock.
 pub struct MultimodalModel {
-    backend: LlamaBackend,
+    backend: &'static LlamaBackend,
     model: LlamaModel,
+    fingerprint: ModelFingerprint,
+    mtmd_ctx: Mutex<MtmdContext>,
+    media_marker: String,
+    // The template embedded in the GGUF, if it has a usable one.
+    chat_template: Option<LlamaChatTemplate>,
+    ctx_size: u32,
+    n_threads: i32,
+    n_batch: u32,
+    flash_attention: FlashAttention,
 }
 
+#[bon]
 impl MultimodalModel {
+    /// Loads the default model files with default settings.
     pub fn load() -> Result<Self> {
-        let _gags = if SHOW_LLAMA_LOGS {
-            None
-        } else {
-            Some((
+        Self::builder().load()
+    }
+
+    #[builder(finish_fn = load)]
+    pub fn new(
+        #[builder(into)] model_path: Option<PathBuf>,
+        #[builder(into)] mmproj_path: Option<PathBuf>,
+        /// Layers to offload to the GPU (default 99, i.e. all of them).
+        n_gpu_layers: Option<u32>,
+        /// Default context window of sessions created from this model (default 4096).
+        ctx_size: Option<u32>,
+        /// Threads for generation and image encoding (default 8).
+        n_threads: Option<i32>,
+        /// Maximum tokens per decode call during prefill (default: `ctx_size`).
+        n_batch: Option<u32>,
+        /// Default for sessions: [`FlashAttention::Enabled`]. Takes a `bool` too.
+        #[builder(into)]
+        flash_attention: Option<FlashAttention>,
+        /// Default [`LlamaLogs::Silent`].
+        logs: Option<LlamaLogs>,
+        /// Fail with [`BindingsError::LoadTimeout`] if loading the model takes longer, e.g.
+        /// on a corrupt GGUF. The load itself can't be interrupted: it keeps running on a
+        /// leaked thread until it finishes or the process exits.
+        load_timeout: Option<Duration>,
+        /// Where prompts put images (default: mtmd's `<__media__>`). See
+        /// [`MultimodalModel::media_marker`].
+        media_marker: Option<String>,
+    ) -> Result<Self> {
+        let model_path = model_path.unwrap_or_else(|| PathBuf::from(MODEL_PATH));
+        let mmproj_path = mmproj_path.unwrap_or_else(|| PathBuf::from(MMPROJ_PATH));
+        let ctx_size = ctx_size.unwrap_or(CTX_SIZE);
+        let n_threads = n_threads.unwrap_or(N_THREADS);
+        let logs = logs.unwrap_or_default();
+        let _gags = match logs {
+            LlamaLogs::Silent => Some((
                 Gag::stdout().map_err(|_| eyre!("Failed to gag stdout"))?,
                 Gag::stderr().map_err(|_| eyre!("Failed to gag stderr"))?,
-            ))
+            )),
+            _ => None,
+        };
+        let _redirects = match &logs {
+            LlamaLogs::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context("Failed to open llama.cpp log file")?;
+                Some((
+                    Redirect::stdout(file.try_clone()?)
+                        .map_err(|_| eyre!("Failed to redirect stdout"))?,
+                    Redirect::stderr(file).map_err(|_| eyre!("Failed to redirect stderr"))?,
+                ))
+            }
+            _ => None,
+        };
+        let backend = backend()?;
+        // Without a tracing hook llama.cpp prints to stderr, which `File` captures.
+        if !matches!(logs, LlamaLogs::File(_)) {
+            send_logs_to_tracing(
+                LogOptions::default().with_logs_enabled(matches!(logs, LlamaLogs::Tracing)),
+            );
+        }
+        let n_gpu_layers = n_gpu_layers.unwrap_or(GPU_LAYERS);
+        let load = move || {
+            let model_params = LlamaModelParams::default().with_n_gpu_layers(n_gpu_layers);
+            LlamaModel::load_from_file(backend, &model_path, &model_params)
+        };
+        let model = match load_timeout {
+            Some(timeout) => {
+                let (tx, rx) = std_mpsc::channel();
+                thread::spawn(move || {
+                    let _ = tx.send(load());
+                });
+                match rx.recv_timeout(timeout) {
+                    Ok(model) => model,
+                    Err(RecvTimeoutError::Timeout) => {
+                        return Err(BindingsError::LoadTimeout(timeout).into())
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Err(eyre!("Model loading thread panicked"))
+                    }
+                }
+            }
+            None => load(),
+        }
+        .context("Failed to load model")?;
+        let fingerprint = ModelFingerprint {
+            arch: model
+                .meta_val_str("general.architecture")
+                .unwrap_or_default(),
+            n_vocab: model.n_vocab(),
+            n_embd: model.n_embd(),
+            n_ctx_train: model.n_ctx_train(),
+        };
+        let media_marker = media_marker.unwrap_or_else(|| mtmd::mtmd_default_marker().to_string());
+        let mtmd_params = MtmdContextParams {
+            use_gpu: true,
+            n_threads,
+            media_marker: CString::new(media_marker.as_str())?,
+            ..Default::default()
+        };
+        let mmproj_path = mmproj_path
+            .to_str()
+            .ok_or_else(|| eyre!("Invalid mmproj path"))?;
+        let mtmd_ctx = MtmdContext::init_from_file(mmproj_path, &model, &mtmd_params)
+            .context("Failed to load mmproj")?;
+        let chat_template = model.chat_template(None).ok();
+
+        Ok(Self {
+            backend,
+            model,
+            fingerprint,
+            mtmd_ctx: Mutex::new(mtmd_ctx),
+            media_marker,
+            chat_template,
+            ctx_size,
+            n_threads,
+            n_batch: n_batch.unwrap_or(ctx_size),
+            flash_attention: flash_attention.unwrap_or_default(),
+        })
+    }
+
+    /// Context length the model was trained with.
+    pub fn n_ctx_train(&self) -> u32 {
+        self.model.n_ctx_train()
+    }
+
+    /// Embedding dimension.
+    pub fn n_embd(&self) -> i32 {
+        self.model.n_embd()
+    }
+
+    pub fn n_vocab(&self) -> i32 {
+        self.model.n_vocab()
+    }
+
+    /// `general.name` from the GGUF, if set.
+    pub fn name(&self) -> Option<String> {
+        self.model.meta_val_str("general.name").ok()
+    }
+
+    /// Default context window of sessions created from this model.
+    pub const fn ctx_size(&self) -> u32 {
+        self.ctx_size
+    }
+
+    /// The placeholder a prompt uses to place an image, e.g. `"Compare <__media__> with
+    /// <__media__>"`. Prompts without one get the images in front.
+    pub fn media_marker(&self) -> &str {
+        &self.media_marker
+    }
+
+    /// Token ids of `text` as the model sees it, e.g. to build a `logit_bias` map.
+    pub fn tokenize(&self, text: &str) -> Result<Vec<i32>> {
+        let tokens = self.model.str_to_token(text, AddBos::Never)?;
+        Ok(tokens.into_iter().map(|token| token.0).collect())
+    }
+
+    pub fn new_session(&self) -> Result<Session<'_>> {
+        Session::with_model(self).build()
+    }
+
+    /// Starts a session on its own worker thread, for use from async code or from a UI
+    /// thread that mustn't block. It keeps its history across calls.
+    pub fn async_session(self: &Arc<Self>) -> AsyncSession {
+        AsyncSession::spawn(Arc::clone(self))
+    }
+}
+
+/// Just the vocabulary and chat template of a GGUF, loaded without weights or GPU.
+///
+/// Counts tokens client-side, e.g. for [`LlamaClient`](crate::api::LlamaClient) talking
+/// to a server running the same model.
+pub struct Tokenizer {
+    model: LlamaModel,
+    chat_template: LlamaChatTemplate,
+}
+
+impl Tokenizer {
+    pub fn load(model_path: impl AsRef<Path>) -> Result<Self> {
+        let params = LlamaModelParams::default()
+            .with_n_gpu_layers(0)
+            .with_vocab_only(true);
+        let model = LlamaModel::load_from_file(backend()?, model_path, &params)
+            .context("Failed to load tokenizer")?;
+        let chat_template = model
+            .chat_template(None)
+            .map_err(|e| eyre!("Model has no usable chat template: {e}"))?;
+        Ok(Self {
+            model,
+            chat_template,
+        })
+    }
+
+    pub fn tokenize(&self, text: &str) -> Result<Vec<i32>> {
+        let tokens = self.model.str_to_token(text, AddBos::Never)?;
+        Ok(tokens.into_iter().map(|token| token.0).collect())
+    }
+
+    /// Tokens of `(role, content)` messages after applying the chat template, including
+    /// the opening of the assistant's answer.
+    pub fn count_chat_tokens(&self, messages: &[(&str, &str)]) -> Result<usize> {
+        let messages = messages
+            .iter()
+            .map(|&(role, content)| LlamaChatMessage::new(role.to_string(), content.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let prompt = self
+            .model
+            .apply_chat_template(&self.chat_template, &messages, true)?;
+        Ok(self.model.str_to_token(&prompt, AddBos::Always)?.len())
+    }
+}
+
+/// An answer being generated on a worker thread, from
+/// [`AsyncSession::stream_in_background`]. Dropping it stops the generation.
+pub struct BackgroundStream {
+    pieces: std_mpsc::Receiver<Result<String>>,
+}
+
+impl BackgroundStream {
+    /// Returns right away: `Pending` while the next piece is still being generated,
+    /// `Ready(None)` once the answer is complete.
+    pub fn try_next(&self) -> Poll<Option<Result<String>>> {
+        match self.pieces.try_recv() {
+            Ok(piece) => Poll::Ready(Some(piece)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+        }
+    }
+
+    /// Like `try_next`, but waits up to `timeout` for the next piece.
+    pub fn next_timeout(&self, timeout: Duration) -> Poll<Option<Result<String>>> {
+        match self.pieces.recv_timeout(timeout) {
+            Ok(piece) => Poll::Ready(Some(piece)),
+            Err(RecvTimeoutError::Timeout) => Poll::Pending,
+            Err(RecvTimeoutError::Disconnected) => Poll::Ready(None),
+        }
+    }
+}
+
+/// Blocks until the next piece is ready.
+impl Iterator for BackgroundStream {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pieces.recv().ok()
+    }
+}
+
+// The worker drops its sender when done, after which `recv` always fails.
+impl FusedIterator for BackgroundStream {}
+
+enum Command {
+    Chat {
+        prompt: String,
+        images: Vec<OwnedImageSource>,
+        pieces: PieceSender,
+    },
+    Reset,
+}
+
+// Where the `AsyncSession` worker sends the pieces of an answer: to an async stream, or to
+// a plain channel for callers outside a runtime.
+enum PieceSender {
+    Async(mpsc::Sender<Result<String>>),
+    Blocking(std_mpsc::SyncSender<Result<String>>),
+}
+
+impl PieceSender {
+    // Waits for room in the channel; `false` once the receiver is gone.
+    fn send(&self, piece: Result<String>) -> bool {
+        match self {
+            Self::Async(tx) => tx.blocking_send(piece).is_ok(),
+            Self::Blocking(tx) => tx.send(piece).is_ok(),
+        }
+    }
+
+    // For the one error sent when the worker is gone, into a channel that is still empty.
+    fn send_now(&self, piece: Result<String>) {
+        match self {
+            Self::Async(tx) => {
+                let _ = tx.try_send(piece);
+            }
+            Self::Blocking(tx) => {
+                let _ = tx.try_send(piece);
+            }
+        }
+    }
+}
+
+// An `ImageSource` that owns its data, to send to the `AsyncSession` worker.
+enum OwnedImageSource {
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+    DataUrl(String),
+    #[cfg(feature = "remote-images")]
+    Url(String),
+}
+
+fn path_sources(images: &[impl AsRef<Path>]) -> Vec<OwnedImageSource> {
+    images
+        .iter()
+        .map(|p| OwnedImageSource::Path(p.as_ref().to_path_buf()))
+        .collect()
+}
+
+impl OwnedImageSource {
+    fn new(source: &ImageSource<'_>) -> Self {
+        match *source {
+            ImageSource::Path(path) => Self::Path(path.to_path_buf()),
+            ImageSource::Bytes(bytes) => Self::Bytes(bytes.to_vec()),
+            ImageSource::DataUrl(url) => Self::DataUrl(url.to_string()),
+            #[cfg(feature = "remote-images")]
+            ImageSource::Url(url) => Self::Url(url.to_string()),
+        }
+    }
+
+    fn as_source(&self) -> ImageSource<'_> {
+        match self {
+            Self::Path(path) => ImageSource::Path(path),
+            Self::Bytes(bytes) => ImageSource::Bytes(bytes),
+            Self::DataUrl(url) => ImageSource::DataUrl(url),
+            #[cfg(feature = "remote-images")]
+            Self::Url(url) => ImageSource::Url(url),
+        }
+    }
+}
+
+/// Async front-end for a local [`Session`].
+///
+/// The session lives on a worker thread (it isn't `Send`, so it can't be moved between
+/// tasks) and keeps its history across calls. Requests are handled one at a time; pieces
+/// come back through a channel. Dropping a stream stops its generation. No tokio runtime
+/// is needed outside the async methods.
+pub struct AsyncSession {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl AsyncSession {
+    fn spawn(model: Arc<MultimodalModel>) -> Self {
+        let (commands, mut rx) = mpsc::unbounded_channel();
+        thread::spawn(move || {
+            let mut session = match model.new_session() {
+                Ok(session) => session,
+                Err(e) => {
+                    let message = format!("Failed to create session: {e}");
+                    while let Some(command) = rx.blocking_recv() {
+                        if let Command::Chat { pieces, .. } = command {
+                            pieces.send(Err(eyre!(message.clone())));
+                        }
+                    }
+                    return;
+                }
+            };
+            while let Some(command) = rx.blocking_recv() {
+                match command {
+                    Command::Chat {
+                        prompt,
+                        images,
+                        pieces,
+                    } => match session
+                        .stream_chat_with(&prompt)
+                        .sources(
+                            &images
+                                .iter()
+                                .map(OwnedImageSource::as_source)
+                                .collect::<Vec<_>>(),
+                        )
+                        .call()
+                    {
+                        Ok(stream) => {
+                            for piece in stream {
+                                if !pieces.send(piece) {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            pieces.send(Err(e));
+                        }
+                    },
+                    Command::Reset => session.reset(),
+                }
+            }
+        });
+        Self { commands }
+    }
+
+    pub fn stream_chat(
+        &self,
+        prompt: &str,
+        images: &[impl AsRef<Path>],
+    ) -> impl Stream<Item = Result<String>> + Send + 'static {
+        self.stream_pieces(prompt, path_sources(images))
+    }
+
+    /// Like [`stream_chat`](Self::stream_chat), with images from any [`ImageSource`], e.g.
+    /// in-memory bytes or `data:` URLs. They are copied, so the stream doesn't borrow them.
+    pub fn stream_chat_sources(
+        &self,
+        prompt: &str,
+        sources: &[ImageSource<'_>],
+    ) -> impl Stream<Item = Result<String>> + Send + 'static {
+        self.stream_pieces(prompt, sources.iter().map(OwnedImageSource::new).collect())
+    }
+
+    /// Generates on the worker and hands pieces over through a channel, so a UI thread can
+    /// poll for them with [`BackgroundStream::try_next`] between other events instead of
+    /// blocking in `decode`. Follow-up calls continue the same conversation.
+    pub fn stream_in_background(
+        &self,
+        prompt: &str,
+        images: &[impl AsRef<Path>],
+    ) -> BackgroundStream {
+        let (tx, pieces) = std_mpsc::sync_channel(64);
+        self.send_chat(prompt, path_sources(images), PieceSender::Blocking(tx));
+        BackgroundStream { pieces }
+    }
+
+    /// Generates on the worker, appending each piece to the returned buffer so a render
+    /// loop can read the partial answer at any time. The handle finishes with the answer,
+    /// which is then in the session's history like any other turn.
+    pub fn stream_into_shared(
+        &self,
+        prompt: &str,
+        images: &[impl AsRef<Path>],
+    ) -> (JoinHandle<Result<()>>, Arc<Mutex<String>>) {
+        let pieces = self.stream_in_background(prompt, images);
+        let shared = Arc::new(Mutex::new(String::new()));
+        let buffer = Arc::clone(&shared);
+        let handle = thread::spawn(move || {
+            for piece in pieces {
+                buffer
+                    .lock()
+                    .map_err(|_| eyre!("Shared buffer lock poisoned"))?
+                    .push_str(&piece?);
+            }
+            Ok(())
+        });
+        (handle, shared)
+    }
+
+    // Streams the pieces the worker sends for this chat.
+    fn stream_pieces(
+        &self,
+        prompt: &str,
+        images: Vec<OwnedImageSource>,
+    ) -> impl Stream<Item = Result<String>> + Send + 'static {
+        let (tx, mut rx) = mpsc::channel(64);
+        self.send_chat(prompt, images, PieceSender::Async(tx));
+        futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx))
+    }
+
+    fn send_chat(&self, prompt: &str, images: Vec<OwnedImageSource>, pieces: PieceSender) {
+        let command = Command::Chat {
+            prompt: prompt.to_string(),
+            images,
+            pieces,
+        };
+        if let Err(mpsc::error::SendError(Command::Chat { pieces, .. })) =
+            self.commands.send(command)
+        {
+            pieces.send_now(Err(eyre!("Session worker has stopped")));
+        }
+    }
+
+    pub async fn chat(&self, prompt: &str, images: &[impl AsRef<Path>]) -> Result<String> {
+        self.stream_chat(prompt, images).try_collect().await
+    }
+
+    pub fn reset(&self) {
+        let _ = self.commands.send(Command::Reset);
+    }
+}
+
+/// Sampler settings for the local backend.
+///
+/// Unset fields fall back to the session's settings, then to the defaults (`temperature`
+/// 0.7, `top_p` 0.8, `repeat_penalty` 1.0, `presence_penalty` 1.5, `frequency_penalty`
+/// 0.0, greedy selection), which match `LlamaClient`'s.
+///
+/// A greedy answer always takes the most likely token, so the truncation and temperature
+/// settings have no effect on it. Otherwise the token is drawn from the distribution left
+/// after `top_k`, `top_p`, `min_p` and `temperature`; with a `seed` the same prompt gives
+/// the same tokens on every run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SamplingParams {
+    pub temperature: Option<f32>,
+    /// Dynamic temperature: scales `temperature` within `temperature ± dynatemp_range` by
+    /// the entropy of each token's distribution, so confident steps stay focused.
+    pub dynatemp_range: Option<f32>,
+    /// How strongly entropy maps to temperature with `dynatemp_range` (default 1.0).
+    pub dynatemp_exponent: Option<f32>,
+    pub top_p: Option<f32>,
+    /// Drops tokens less likely than `min_p` times the most likely one.
+    pub min_p: Option<f32>,
+    pub top_k: Option<i32>,
+    pub repeat_penalty: Option<f32>,
+    /// How many recent tokens the penalties look at; `-1` (default) for the whole context.
+    pub repeat_last_n: Option<i32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// Seeds the distribution sampling, for reproducible output.
+    pub seed: Option<u32>,
+    /// Whether to decode greedily. Defaults to greedy unless a `seed` is set.
+    pub greedy: Option<bool>,
+    /// Added to the logits of these token ids; a large negative bias forbids a token. Look
+    /// ids up with [`MultimodalModel::tokenize`].
+    pub logit_bias: HashMap<i32, f32>,
+    /// Replaces the chain built from the settings above (which are then ignored, apart
+    /// from `seed` and `logit_bias`), e.g. to run `TopK` after `TopP` or to use
+    /// `Mirostat`. A grammar still goes right before the terminal stage.
+    pub chain: Option<Vec<SamplerStage>>,
+}
+
+impl SamplingParams {
+    fn or(&self, fallback: &Self) -> Self {
+        Self {
+            temperature: self.temperature.or(fallback.temperature),
+            dynatemp_range: self.dynatemp_range.or(fallback.dynatemp_range),
+            dynatemp_exponent: self.dynatemp_exponent.or(fallback.dynatemp_exponent),
+            top_p: self.top_p.or(fallback.top_p),
+            min_p: self.min_p.or(fallback.min_p),
+            top_k: self.top_k.or(fallback.top_k),
+            repeat_penalty: self.repeat_penalty.or(fallback.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.or(fallback.repeat_last_n),
+            presence_penalty: self.presence_penalty.or(fallback.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(fallback.frequency_penalty),
+            seed: self.seed.or(fallback.seed),
+            greedy: self.greedy.or(fallback.greedy),
+            logit_bias: if self.logit_bias.is_empty() {
+                fallback.logit_bias.clone()
+            } else {
+                self.logit_bias.clone()
+            },
+            chain: self.chain.clone().or_else(|| fallback.chain.clone()),
+        }
+    }
+
+    fn build_sampler(&self, model: &LlamaModel, grammar: Option<&str>) -> Result<LlamaSampler> {
+        let chain = match &self.chain {
+            Some(chain) => {
+                validate_chain(chain)?;
+                Cow::Borrowed(chain)
+            }
+            None => Cow::Owned(self.default_chain()),
+        };
+        let Some((terminal, body)) = chain.split_last() else {
+            return Err(BindingsError::InvalidSamplerChain("it is empty").into());
+        };
+        // llama.cpp picks a random seed for `LLAMA_DEFAULT_SEED`.
+        let seed = self.seed.unwrap_or(u32::MAX);
+        let mut stages = Vec::new();
+        if !self.logit_bias.is_empty() {
+            let biases: Vec<LlamaLogitBias> = self
+                .logit_bias
+                .iter()
+                .map(|(&token, &bias)| LlamaLogitBias::new(LlamaToken(token), bias))
+                .collect();
+            stages.push(LlamaSampler::logit_bias(model.n_vocab(), &biases));
+        }
+        stages.extend(body.iter().map(|stage| stage.sampler(model, seed)));
+        if let Some(grammar) = grammar {
+            let grammar = LlamaSampler::grammar(model, grammar, "root")
+                .map_err(|e| eyre!("Invalid grammar: {e}"))?;
+            stages.push(grammar);
+        }
+        stages.push(terminal.sampler(model, seed));
+        Ok(LlamaSampler::chain_simple(stages))
+    }
+
+    // The chain the individual settings describe.
+    fn default_chain(&self) -> Vec<SamplerStage> {
+        let mut chain = vec![SamplerStage::Penalties {
+            last_n: self.repeat_last_n.unwrap_or(-1),
+            repeat: self.repeat_penalty.unwrap_or(1.0),
+            frequency: self.frequency_penalty.unwrap_or(0.0),
+            presence: self.presence_penalty.unwrap_or(1.5),
+        }];
+        let greedy = self.greedy.unwrap_or_else(|| self.seed.is_none());
+        // Truncation and temperature never change the argmax, so greedy decoding skips them.
+        if greedy {
+            chain.push(SamplerStage::Greedy);
+            return chain;
+        }
+        chain.extend(self.top_k.map(SamplerStage::TopK));
+        chain.push(SamplerStage::TopP(self.top_p.unwrap_or(0.8)));
+        chain.extend(self.min_p.map(SamplerStage::MinP));
+        let temperature = self.temperature.unwrap_or(0.7);
+        chain.push(match self.dynatemp_range {
+            Some(range) if range > 0.0 => SamplerStage::DynamicTemperature {
+                temperature,
+                range,
+                exponent: self.dynatemp_exponent.unwrap_or(1.0),
+            },
+            _ => SamplerStage::Temperature(temperature),
+        });
+        chain.push(SamplerStage::Dist);
+        chain
+    }
+}
+
+/// A step of a custom sampler chain (see [`SamplingParams::chain`]).
+///
+/// The last one must be the only terminal stage (`Greedy`, `Dist`, `Mirostat` or
+/// `MirostatV2`), which picks the token; stages that use randomness take the params'
+/// `seed`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum SamplerStage {
+    /// `last_n` of `-1` penalizes repeats across the whole context.
+    Penalties {
+        last_n: i32,
+        repeat: f32,
+        frequency: f32,
+        presence: f32,
+    },
+    TopK(i32),
+    TopP(f32),
+    MinP(f32),
+    Typical(f32),
+    Temperature(f32),
+    DynamicTemperature {
+        temperature: f32,
+        range: f32,
+        exponent: f32,
+    },
+    /// Exclude Top Choices: with `probability`, drops every token above `threshold` but
+    /// the least likely of them.
+    Xtc {
+        probability: f32,
+        threshold: f32,
+    },
+    Greedy,
+    Dist,
+    Mirostat {
+        tau: f32,
+        eta: f32,
+    },
+    MirostatV2 {
+        tau: f32,
+        eta: f32,
+    },
+}
+
+impl SamplerStage {
+    const fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            Self::Greedy | Self::Dist | Self::Mirostat { .. } | Self::MirostatV2 { .. }
+        )
+    }
+
+    fn sampler(&self, model: &LlamaModel, seed: u32) -> LlamaSampler {
+        match *self {
+            Self::Penalties {
+                last_n,
+                repeat,
+                frequency,
+                presence,
+            } => LlamaSampler::penalties(last_n, repeat, frequency, presence),
+            Self::TopK(k) => LlamaSampler::top_k(k),
+            Self::TopP(p) => LlamaSampler::top_p(p, 1),
+            Self::MinP(p) => LlamaSampler::min_p(p, 1),
+            Self::Typical(p) => LlamaSampler::typical(p, 1),
+            Self::Temperature(t) => LlamaSampler::temp(t),
+            Self::DynamicTemperature {
+                temperature,
+                range,
+                exponent,
+            } => LlamaSampler::temp_ext(temperature, range, exponent),
+            Self::Xtc {
+                probability,
+                threshold,
+            } => LlamaSampler::xtc(probability, threshold, 1, seed),
+            Self::Greedy => LlamaSampler::greedy(),
+            Self::Dist => LlamaSampler::dist(seed),
+            Self::Mirostat { tau, eta } => {
+                LlamaSampler::mirostat(model.n_vocab(), seed, tau, eta, 100)
+            }
+            Self::MirostatV2 { tau, eta } => LlamaSampler::mirostat_v2(seed, tau, eta),
+        }
+    }
+}
+
+fn validate_chain(chain: &[SamplerStage]) -> Result<(), BindingsError> {
+    let invalid = |reason| Err(BindingsError::InvalidSamplerChain(reason));
+    match chain.iter().filter(|stage| stage.is_terminal()).count() {
+        0 => invalid("it needs a terminal stage (Greedy, Dist, Mirostat or MirostatV2)"),
+        1 if chain.last().is_some_and(SamplerStage::is_terminal) => Ok(()),
+        1 => invalid("the terminal stage must come last"),
+        _ => invalid("it has more than one terminal stage"),
+    }
+}
+
+/// How `Session::ocr_tiled` splits an image: `columns` x `rows` tiles, each grown by
+/// `overlap` pixels on every side so text on a seam is fully inside at least one tile.
+#[derive(Debug, Clone, Copy)]
+pub struct TileGrid {
+    pub columns: u32,
+    pub rows: u32,
+    pub overlap: u32,
+}
+
+impl Default for TileGrid {
+    fn default() -> Self {
+        Self {
+            columns: 2,
+            rows: 2,
+            overlap: 32,
+        }
+    }
+}
+
+/// Prefill progress for multi-image prompts: `chunk` (1-based) of `total_chunks` is about
+/// to be evaluated. Each image is one chunk, followed by the trailing prompt text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefillProgress {
+    pub chunk: usize,
+    pub total_chunks: usize,
+}
+
+/// When a `Session` clears its context on its own, checked at the start of every chat.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AutoReset {
+    /// Only `reset()` clears the context (the default).
+    #[default]
+    Never,
+    /// Every chat starts from an empty context, so turns are independent.
+    BeforeEachChat,
+    /// Reset once more than this fraction (0.0-1.0) of the context window is in use.
+    WhenContextExceedsFraction(f32),
+}
+
+#[derive(Debug, Clone, Default)]
+struct ChatOptions {
+    sampling: SamplingParams,
+    max_new_tokens: Option<usize>,
+    stop: Vec<String>,
+    grammar: Option<String>,
+    assistant_prefix: Option<String>,
+    // Image descriptions for the transcript.
+    images: Vec<String>,
+}
+
+/// Identifies the text model a saved session state belongs to, by its GGUF metadata
+/// rather than its path, so the same weights match however they were opened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ModelFingerprint {
+    arch: String,
+    n_vocab: i32,
+    n_embd: i32,
+    n_ctx_train: u32,
+}
+
+impl fmt::Display for ModelFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (n_vocab {}, n_embd {}, n_ctx_train {})",
+            self.arch, self.n_vocab, self.n_embd, self.n_ctx_train
+        )
+    }
+}
+
+/// First line of a `Session::save_state` file; the raw llama.cpp state follows it.
+#[derive(Debug, Serialize, Deserialize)]
+struct StateHeader {
+    model: ModelFingerprint,
+    ctx_size: u32,
+    n_past: i32,
+}
+
+pub struct Session<'a> {
+    model: &'a LlamaModel,
+    fingerprint: &'a ModelFingerprint,
+    context: LlamaContext<'a>,
+    mtmd_ctx: &'a Mutex<MtmdContext>,
+    media_marker: &'a str,
+    chat_template: Cow<'a, LlamaChatTemplate>,
+    batch: LlamaBatch<'a>,
+    n_batch: i32,
+    n_past: i32,
+    sampling: SamplingParams,
+    max_new_tokens: Option<usize>,
+    stop_tokens: Vec<LlamaToken>,
+    auto_reset: AutoReset,
+    transcript: Option<Arc<TranscriptLogger>>,
+    parallel: u32,
+    max_image_edge: Option<u32>,
+    show_special_tokens: bool,
+    // Every decode goes through this; tests swap in a failing one to check recovery.
+    decode: DecodeFn,
+}
+
+type DecodeFn = fn(&mut LlamaContext<'_>, &mut LlamaBatch<'_>) -> Result<()>;
+
+fn llama_decode(context: &mut LlamaContext<'_>, batch: &mut LlamaBatch<'_>) -> Result<()> {
+    context
+        .decode(batch)
+        .map_err(|e| eyre!("Decode failed: {e}"))
+}
+
+// One prompt of a `chat_batch` group, decoding in its own sequence.
+struct BatchSequence {
+    seq_id: i32,
+    n_past: i32,
+    sampler: LlamaSampler,
+    next: Option<LlamaToken>,
+    n_generated: usize,
+    output: String,
+}
+
+#[bon]
+impl<'a> Session<'a> {
+    #[builder(start_fn = with_model)]
+    #[allow(clippy::similar_names)]
+    pub fn new(
+        #[builder(start_fn)] multimodal: &'a MultimodalModel,
+        sampling: Option<SamplingParams>,
+        max_new_tokens: Option<usize>,
+        /// Context window of this session (default: the model's `ctx_size`).
+        ctx_size: Option<u32>,
+        /// Threads used while generating, one token at a time (default: the model's
+        /// `n_threads`).
+        n_threads: Option<i32>,
+        /// Threads used for prompt/image prefill, which scales with more cores (default: the
+        /// number of physical cores).
+        n_threads_batch: Option<i32>,
+        /// Token ids that end generation in addition to the model's EOG tokens, for GGUFs
+        /// whose `<|im_end|>` isn't flagged as end-of-generation.
+        stop_tokens: Option<Vec<LlamaToken>>,
+        auto_reset: Option<AutoReset>,
+        /// Appends a record of every completed generation.
+        transcript: Option<Arc<TranscriptLogger>>,
+        /// Chat template to format prompts with instead of the one embedded in the GGUF,
+        /// for models whose template is missing or broken.
+        chat_template: Option<&str>,
+        /// Prompts `chat_batch` decodes at once (default 1). Every sequence gets
+        /// `ctx_size / parallel` positions, which also limits regular chats.
+        parallel: Option<u32>,
+        /// Downscales larger images to fit in this many pixels per side before encoding,
+        /// keeping the aspect ratio, to bound the context positions each one takes.
+        max_image_edge: Option<u32>,
+        /// Include special tokens such as `<|im_start|>` in answers, for debugging
+        /// templates (default `false`: they are still decoded, but not shown).
+        show_special_tokens: Option<bool>,
+        /// Default: the model's setting. Takes a `bool` too.
+        #[builder(into)]
+        flash_attention: Option<FlashAttention>,
+        /// Maximum tokens per decode call during prefill (default: the model's `n_batch`,
+        /// capped at `ctx_size`).
+        n_batch: Option<u32>,
+        /// Tokens per physical batch, which sizes the compute buffers; lower it to save
+        /// VRAM on short prompts (default: `n_batch`).
+        n_ubatch: Option<u32>,
+    ) -> Result<Self> {
+        let parallel = parallel.unwrap_or(1).max(1);
+        let model = &multimodal.model;
+        let ctx_size = ctx_size.unwrap_or(multimodal.ctx_size);
+        // Quality degrades past the trained length, but it's allowed (e.g. with RoPE scaling).
+        if ctx_size > model.n_ctx_train() {
+            warn!(
+                "ctx_size {ctx_size} exceeds the model's trained context length {}",
+                model.n_ctx_train()
+            );
+        }
+        let n_threads_batch = n_threads_batch
+            .unwrap_or_else(|| i32::try_from(num_cpus::get_physical()).unwrap_or(i32::MAX));
+        let flash_attention = flash_attention.unwrap_or(multimodal.flash_attention);
+        let n_batch = n_batch.unwrap_or_else(|| multimodal.n_batch.min(ctx_size));
+        let ctx_params = LlamaContextParams::default()
+            .with_n_ctx(NonZeroU32::new(ctx_size))
+            .with_flash_attention_policy(flash_attention.policy())
+            .with_n_threads(n_threads.unwrap_or(multimodal.n_threads))
+            .with_n_threads_batch(n_threads_batch)
+            .with_n_batch(n_batch)
+            .with_n_ubatch(n_ubatch.unwrap_or(n_batch))
+            .with_n_seq_max(parallel);
+        let context = model.new_context(multimodal.backend, ctx_params)?;
+        let chat_template = match (chat_template, &multimodal.chat_template) {
+            (Some(template), _) => Cow::Owned(LlamaChatTemplate::new(template)?),
+            (None, Some(embedded)) => Cow::Borrowed(embedded),
+            (None, None) => {
+                return Err(eyre!(
+                    "Model has no usable chat template; pass one with the session builder's \
+                     `chat_template`"
+                ))
+            }
+        };
+
+        Ok(Self {
+            model,
+            fingerprint: &multimodal.fingerprint,
+            context,
+            mtmd_ctx: &multimodal.mtmd_ctx,
+            media_marker: &multimodal.media_marker,
+            chat_template,
+            batch: LlamaBatch::new(ctx_size as usize, 1),
+            n_batch: i32::try_from(n_batch)?,
+            n_past: 0,
+            sampling: sampling.unwrap_or_default(),
+            max_new_tokens,
+            stop_tokens: stop_tokens.unwrap_or_default(),
+            auto_reset: auto_reset.unwrap_or_default(),
+            transcript,
+            parallel,
+            max_image_edge,
+            show_special_tokens: show_special_tokens.unwrap_or_default(),
+            decode: llama_decode,
+        })
+    }
+
+    fn default_options(&self) -> ChatOptions {
+        ChatOptions {
+            sampling: self.sampling.clone(),
+            max_new_tokens: self.max_new_tokens,
+            stop: Vec::new(),
+            grammar: None,
+            assistant_prefix: None,
+            images: Vec::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.context.clear_kv_cache();
+        self.n_past = 0;
+    }
+
+    /// Writes the KV cache and position to `path`, so a later process can skip
+    /// re-evaluating the same system prompt and images with [`Session::load_state`].
+    pub fn save_state(&self, path: impl AsRef<Path>) -> Result<()> {
+        let header = StateHeader {
+            model: self.fingerprint.clone(),
+            ctx_size: self.context.n_ctx(),
+            n_past: self.n_past,
+        };
+        let mut state = vec![0; self.context.get_state_size()];
+        // SAFETY: `state` is as large as llama.cpp says the state can be.
+        let written = unsafe { self.context.copy_state_data(state.as_mut_ptr()) };
+        state.truncate(written);
+        let mut file = BufWriter::new(File::create(path).context("Failed to create state file")?);
+        serde_json::to_writer(&mut file, &header)?;
+        file.write_all(b"\n")?;
+        file.write_all(&state)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Restores a state written by [`Session::save_state`]; the next chat continues from it.
+    /// Fails if the state was saved with a different model or context size.
+    pub fn load_state(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let bytes = fs::read(path).context("Failed to read state file")?;
+        let header_len = bytes
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| eyre!("Not a session state file"))?;
+        let header: StateHeader =
+            serde_json::from_slice(&bytes[..header_len]).context("Not a session state file")?;
+        if header.model != *self.fingerprint {
+            return Err(eyre!(
+                "State was saved with model {}, not {}",
+                header.model,
+                self.fingerprint
+            ));
+        }
+        if header.ctx_size != self.context.n_ctx() {
+            return Err(eyre!(
+                "State was saved with ctx_size {}, not {}",
+                header.ctx_size,
+                self.context.n_ctx()
+            ));
+        }
+        self.reset();
+        // SAFETY: the header matches, so the state was written for this model and context
+        // size.
+        let read = unsafe { self.context.set_state_data(&bytes[header_len + 1..]) };
+        if read == 0 {
+            return Err(eyre!("Failed to restore session state"));
+        }
+        self.n_past = header.n_past;
+        Ok(())
+    }
+
+    pub fn chat(&mut self, prompt: &str, images: &[impl AsRef<Path>]) -> Result<String> {
+        self.stream_chat(prompt, images)?.collect()
+    }
+
+    /// Like [`chat`](Self::chat), with images given as raw `(rgb, width, height)` frames
+    /// (3 bytes per pixel, row by row), e.g. from a camera, so nothing is encoded or
+    /// decoded. `max_image_edge` doesn't apply to them.
+    pub fn chat_with_rgb(
+        &mut self,
+        prompt: &str,
+        frames: &[(Vec<u8>, u32, u32)],
+    ) -> Result<String> {
+        let bitmaps = frames
+            .iter()
+            .map(|(rgb, width, height)| {
+                let expected = u64::from(*width) * u64::from(*height) * 3;
+                if u64::try_from(rgb.len())? != expected {
+                    return Err(eyre!(
+                        "RGB frame of {width}x{height} needs {expected} bytes, got {}",
+                        rgb.len()
+                    ));
+                }
+                Ok(MtmdBitmap::from_image_data(*width, *height, rgb)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let options = ChatOptions {
+            images: frames
+                .iter()
+                .map(|(_, width, height)| format!("<rgb frame {width}x{height}>"))
+                .collect(),
+            ..self.default_options()
         };
-        let backend = LlamaBackend::init().context("Failed to init backend")?;
-        send_logs_to_tracing(LogOptions::default().with_logs_enabled(SHOW_LLAMA_LOGS));
-        let model_params = LlamaModelParams::default().with_n_gpu_layers(GPU_LAYERS);
-        let model = LlamaModel::load_from_file(&backend, MODEL_PATH, &model_params)
-            .context("Failed to load model")?;
+        self.stream_chat_bitmaps(prompt, &bitmaps, &options, None)?
+            .collect()
+    }
+
+    /// Streams an answer about several images at once, e.g. "What changed between these?".
+    /// Each image is put in front of the prompt labelled "Image 1", "Image 2", ... in the
+    /// order given, so the prompt can refer to them by number.
+    pub fn compare(&mut self, prompt: &str, images: &[&Path]) -> Result<ResponseStream<'a, '_>> {
+        if images.is_empty() {
+            return Err(eyre!("Comparing needs at least one image"));
+        }
+        let labelled = labelled_prompt(prompt, images.len(), self.media_marker);
+        self.stream_chat(&labelled, images)
+    }
+
+    /// Calls `on_token` with every piece as it's generated, stopping early when it returns
+    /// `ControlFlow::Break`. Returns the text generated up to that point.
+    pub fn generate_with_callback(
+        &mut self,
+        prompt: &str,
+        images: &[impl AsRef<Path>],
+        mut on_token: impl FnMut(&str) -> ControlFlow<()>,
+    ) -> Result<String> {
+        let mut answer = String::new();
+        for piece in self.stream_chat(prompt, images)? {
+            let piece = piece?;
+            answer.push_str(&piece);
+            if on_token(&piece).is_break() {
+                break;
+            }
+        }
+        Ok(answer)
+    }
+
+    /// Like [`Session::chat`], but stops as soon as the answer so far satisfies `predicate`,
+    /// e.g. `|text| text.contains('}')`, and returns it.
+    pub fn chat_until(
+        &mut self,
+        prompt: &str,
+        images: &[impl AsRef<Path>],
+        predicate: impl Fn(&str) -> bool,
+    ) -> Result<String> {
+        let mut answer = String::new();
+        self.generate_with_callback(prompt, images, |piece| {
+            answer.push_str(piece);
+            if predicate(&answer) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+    }
+
+    pub fn stream_chat(
+        &mut self,
+        prompt: &str,
+        images: &[impl AsRef<Path>],
+    ) -> Result<ResponseStream<'a, '_>> {
+        let bitmaps = self.load_bitmaps(images)?;
+        let options = ChatOptions {
+            images: describe_paths(images),
+            ..self.default_options()
+        };
+        self.stream_chat_bitmaps(prompt, &bitmaps, &options, None)
+    }
+
+    /// The tokens `stream_chat` would evaluate for `prompt`, paired with their text, without
+    /// evaluating them. Each image is a single `(-1, "<image: N tokens>")` entry, where N is
+    /// how many positions its embedding takes.
+    pub fn debug_tokenize(
+        &self,
+        prompt: &str,
+        images: &[impl AsRef<Path>],
+    ) -> Result<Vec<(i32, String)>> {
+        let bitmaps = self.load_bitmaps(images)?;
+        let input = MtmdInputText {
+            text: self.format_prompt(prompt, bitmaps.len())?,
+            add_special: true,
+            parse_special: true,
+        };
+        let bitmap_refs: Vec<&MtmdBitmap> = bitmaps.iter().collect();
+        let chunks = self.mtmd().tokenize(input, &bitmap_refs)?;
+        let mut tokens = Vec::new();
+        for chunk in (0..chunks.len()).filter_map(|i| chunks.get(i)) {
+            let Some(text_tokens) = chunk.text_tokens() else {
+                let kind = match chunk.chunk_type() {
+                    MtmdInputChunkType::Audio => "audio",
+                    _ => "image",
+                };
+                tokens.push((-1, format!("<{kind}: {} tokens>", chunk.n_tokens())));
+                continue;
+            };
+            for &token in text_tokens {
+                tokens.push((token.0, self.model.token_to_str(token, Special::Tokenize)?));
+            }
+        }
+        Ok(tokens)
+    }
+
+    #[builder]
+    pub fn chat_with(
+        &mut self,
+        #[builder(start_fn)] prompt: &str,
+        images: Option<&[&Path]>,
+        sources: Option<&[ImageSource<'_>]>,
+        sampling: Option<SamplingParams>,
+        max_new_tokens: Option<usize>,
+        stop: Option<Vec<String>>,
+        grammar: Option<&str>,
+        json_schema: Option<&serde_json::Value>,
+        assistant_prefix: Option<&str>,
+        on_prefill: Option<&mut dyn FnMut(PrefillProgress)>,
+    ) -> Result<String> {
+        self.stream_chat_with(prompt)
+            .maybe_grammar(grammar)
+            .maybe_json_schema(json_schema)
+            .maybe_assistant_prefix(assistant_prefix)
+            .maybe_on_prefill(on_prefill)
+            .maybe_images(images)
+            .maybe_sources(sources)
+            .maybe_sampling(sampling)
+            .maybe_max_new_tokens(max_new_tokens)
+            .maybe_stop(stop)
+            .call()?
+            .collect()
+    }
+
+    #[builder]
+    pub fn stream_chat_with(
+        &mut self,
+        #[builder(start_fn)] prompt: &str,
+        images: Option<&[&Path]>,
+        /// Images from in-memory bytes or URLs (see [`ImageSource`]), placed after
+        /// `images`. Paths are always read as files, never as URLs.
+        sources: Option<&[ImageSource<'_>]>,
+        sampling: Option<SamplingParams>,
+        /// Stops after this many pieces; overrides the session's `max_new_tokens`.
+        max_new_tokens: Option<usize>,
+        /// Ends generation when the output contains one of these strings. The match itself
+        /// is not emitted, even when it spans several pieces.
+        stop: Option<Vec<String>>,
+        /// GBNF grammar (with a `root` rule) the output must follow.
+        grammar: Option<&str>,
+        /// JSON schema the output must match; converted to a grammar with
+        /// [`json_schema_grammar`]. Can't be combined with `grammar`.
+        json_schema: Option<&serde_json::Value>,
+        /// Start of the answer, e.g. `{"name": "`. The model continues from it, and the
+        /// stream yields it as the first piece.
+        assistant_prefix: Option<&str>,
+        /// Called before each image (and the trailing text) is evaluated, so a UI can show
+        /// "processing image 2 of 3" during long prefills.
+        on_prefill: Option<&mut dyn FnMut(PrefillProgress)>,
+    ) -> Result<ResponseStream<'a, '_>> {
+        let grammar = match (grammar, json_schema) {
+            (Some(_), Some(_)) => return Err(eyre!("Pass either a grammar or a JSON schema")),
+            (Some(grammar), None) => Some(grammar.to_string()),
+            (None, Some(schema)) => Some(json_schema_grammar(schema)?),
+            (None, None) => None,
+        };
+        let images = images.unwrap_or_default();
+        let sources = sources.unwrap_or_default();
+        let mut bitmaps = self.load_bitmaps(images)?;
+        for source in sources {
+            bitmaps.push(self.load_source(*source)?);
+        }
+        let mut image_refs = describe_paths(images);
+        image_refs.extend(sources.iter().map(ImageSource::describe));
+        let options = ChatOptions {
+            sampling: sampling.unwrap_or_default().or(&self.sampling),
+            max_new_tokens: max_new_tokens.or(self.max_new_tokens),
+            stop: stop.unwrap_or_default(),
+            grammar,
+            assistant_prefix: assistant_prefix.map(ToString::to_string),
+            images: image_refs,
+        };
+        self.stream_chat_bitmaps(prompt, &bitmaps, &options, on_prefill)
+    }
+
+    /// OCRs a large image tile by tile so small text survives the projector's resolution
+    /// limit, returning the tile transcripts in reading order (rows top to bottom, tiles
+    /// left to right). Each tile is transcribed in a fresh context, so this resets the
+    /// session. Lines crossing a seam may appear twice because of the overlap.
+    pub fn ocr_tiled(&mut self, path: impl AsRef<Path>, grid: TileGrid) -> Result<String> {
+        if grid.columns == 0 || grid.rows == 0 {
+            return Err(eyre!("Tile grid needs at least one column and one row"));
+        }
+        let image = image::open(path.as_ref()).context("Failed to open image")?;
+        let (width, height) = (image.width(), image.height());
+        let (tile_w, tile_h) = (width.div_ceil(grid.columns), height.div_ceil(grid.rows));
+        let mut transcripts = Vec::new();
+        for row in 0..grid.rows {
+            for col in 0..grid.columns {
+                let x0 = (col * tile_w).saturating_sub(grid.overlap);
+                let y0 = (row * tile_h).saturating_sub(grid.overlap);
+                let x1 = ((col + 1) * tile_w + grid.overlap).min(width);
+                let y1 = ((row + 1) * tile_h + grid.overlap).min(height);
+                if x0 >= x1 || y0 >= y1 {
+                    continue;
+                }
+                let tile = image.crop_imm(x0, y0, x1 - x0, y1 - y0).to_rgb8();
+                let bitmap =
+                    MtmdBitmap::from_image_data(tile.width(), tile.height(), tile.as_raw())?;
+                self.reset();
+                let options = ChatOptions {
+                    images: vec![format!(
+                        "{} [{x0},{y0} {}x{}]",
+                        path.as_ref().display(),
+                        x1 - x0,
+                        y1 - y0
+                    )],
+                    ..self.default_options()
+                };
+                let text: String = self
+                    .stream_chat_bitmaps(OCR_PROMPT, &[bitmap], &options, None)?
+                    .collect::<Result<_>>()?;
+                transcripts.push(text.trim().to_string());
+            }
+        }
+        self.reset();
+        Ok(transcripts.join("\n"))
+    }
+
+    fn load_bitmaps(&self, images: &[impl AsRef<Path>]) -> Result<Vec<MtmdBitmap>> {
+        images
+            .iter()
+            .map(|p| self.load_source(ImageSource::Path(p.as_ref())))
+            .collect()
+    }
+
+    // Decodes JPEG, PNG, BMP, GIF (first frame) and WebP; see `images::normalize`.
+    fn load_source(&self, source: ImageSource) -> Result<MtmdBitmap> {
+        let bytes = source.read().context("Failed to read image")?;
+        let (bytes, _mime_type) = normalize(&bytes, None)?;
+        if let Some(image) = self
+            .max_image_edge
+            .map(|max_edge| downscale(&bytes, max_edge))
+            .transpose()?
+            .flatten()
+        {
+            let rgb = image.to_rgb8();
+            return Ok(MtmdBitmap::from_image_data(
+                rgb.width(),
+                rgb.height(),
+                rgb.as_raw(),
+            )?);
+        }
+        Ok(MtmdBitmap::from_buffer(&self.mtmd(), &bytes)?)
+    }
+
+    pub async fn chat_with_loader(
+        &mut self,
+        prompt: &str,
+        image_keys: &[&str],
+        loader: &impl ImageLoader,
+    ) -> Result<String> {
+        self.stream_chat_with_loader(prompt, image_keys, loader)
+            .await?
+            .collect()
+    }
+
+    pub async fn stream_chat_with_loader(
+        &mut self,
+        prompt: &str,
+        image_keys: &[&str],
+        loader: &impl ImageLoader,
+    ) -> Result<ResponseStream<'a, '_>> {
+        let mut bitmaps = Vec::new();
+        for key in image_keys {
+            let (bytes, _mime_type) = loader.load(key).await?;
+            bitmaps.push(self.load_source(ImageSource::Bytes(&bytes))?);
+        }
+        let options = ChatOptions {
+            images: image_keys.iter().map(ToString::to_string).collect(),
+            ..self.default_options()
+        };
+        self.stream_chat_bitmaps(prompt, &bitmaps, &options, None)
+    }
+
+    fn stream_chat_bitmaps(
+        &mut self,
+        prompt: &str,
+        bitmaps: &[MtmdBitmap],
+        options: &ChatOptions,
+        on_prefill: Option<&mut dyn FnMut(PrefillProgress)>,
+    ) -> Result<ResponseStream<'a, '_>> {
+        self.apply_auto_reset();
+        // Built first so a bad grammar fails before the (slow) prefill.
+        let mut sampler = options
+            .sampling
+            .build_sampler(self.model, options.grammar.as_deref())?;
+        let marker = self.media_marker;
+        let mut formatted = self.format_prompt(prompt, bitmaps.len())?;
+        // Thinking templates open the reasoning block in the generation prompt already.
+        let starts_in_reasoning = formatted.trim_end().ends_with(THINK_OPEN);
+        let prefix = options
+            .assistant_prefix
+            .clone()
+            .filter(|prefix| !prefix.is_empty());
+        if let Some(prefix) = &prefix {
+            // The prefix counts as generated text: the grammar and penalties see it too.
+            for token in self.model.str_to_token(prefix, AddBos::Never)? {
+                sampler.accept(token);
+            }
+            formatted.push_str(prefix);
+        }
+        let prefill_start = Instant::now();
+        match on_prefill {
+            Some(on_prefill) => {
+                self.prefill_in_segments(&formatted, marker, bitmaps, on_prefill)?;
+            }
+            None => self.prefill(formatted, bitmaps, true, true)?,
+        }
+        let prompt_eval = prefill_start.elapsed();
+        let transcript = self.transcript.is_some().then(|| {
+            let parameters = serde_json::json!({
+                "sampling": options.sampling,
+                "max_new_tokens": options.max_new_tokens,
+                "stop": options.stop,
+                "grammar": options.grammar,
+                "assistant_prefix": options.assistant_prefix,
+            });
+            TranscriptRecord::new("local", prompt, options.images.clone(), parameters)
+        });
+
+        Ok(ResponseStream {
+            session: self,
+            sampler,
+            is_done: false,
+            max_new_tokens: options.max_new_tokens,
+            n_generated: 0,
+            stop: options.stop.clone(),
+            pending: String::new(),
+            transcript,
+            output: String::new(),
+            starts_in_reasoning,
+            prefix,
+            prompt_eval,
+            generation: Duration::ZERO,
+        })
+    }
+
+    fn apply_auto_reset(&mut self) {
+        let should_reset = match self.auto_reset {
+            AutoReset::Never => false,
+            AutoReset::BeforeEachChat => self.n_past > 0,
+            AutoReset::WhenContextExceedsFraction(fraction) => {
+                #[allow(clippy::cast_precision_loss)]
+                let used = self.n_past as f32 / self.seq_capacity() as f32;
+                used > fraction
+            }
+        };
+        if should_reset {
+            self.reset();
+        }
+    }
+
+    // Images go where the prompt has media markers, or all in front if it has none.
+    fn format_prompt(&self, prompt: &str, n_images: usize) -> Result<String> {
+        let marker = self.media_marker;
+        let markers = prompt.matches(marker).count();
+        let full_prompt = if markers == 0 && n_images > 0 {
+            format!("{} {prompt}", marker.repeat(n_images))
+        } else if markers == n_images {
+            prompt.to_string()
+        } else {
+            return Err(BindingsError::MarkerMismatch {
+                markers,
+                images: n_images,
+            }
+            .into());
+        };
+        let messages = vec![LlamaChatMessage::new("user".to_string(), full_prompt)?];
+        Ok(self
+            .model
+            .apply_chat_template(&self.chat_template, &messages, true)?)
+    }
+
+    /// Answers independent single-turn prompts, decoding up to `parallel` (see the builder)
+    /// of them at once, each in its own sequence. Uses the session's sampling settings and
+    /// clears its history.
+    pub fn chat_batch(&mut self, prompts: &[(&str, &[&Path])]) -> Result<Vec<String>> {
+        let group_size = usize::try_from(self.parallel)?;
+        let result = prompts
+            .chunks(group_size)
+            .try_fold(Vec::new(), |mut answers, group| {
+                self.reset();
+                answers.extend(self.decode_group(group)?);
+                Ok(answers)
+            });
+        self.reset();
+        result
+    }
+
+    fn decode_group(&mut self, group: &[(&str, &[&Path])]) -> Result<Vec<String>> {
+        let options = self.default_options();
+        let seq_capacity = i32::try_from(self.seq_capacity())?;
+        let mut sequences = Vec::with_capacity(group.len());
+        // Prompts are evaluated one by one, each followed by sampling its first token while
+        // its logits are still the latest ones.
+        for (seq_id, (prompt, images)) in (0..).zip(group) {
+            let bitmaps = self.load_bitmaps(images)?;
+            let formatted = self.format_prompt(prompt, bitmaps.len())?;
+            let n_past = self.eval_prompt(formatted, &bitmaps, true, 0, seq_id, true)?;
+            let mut sampler = options.sampling.build_sampler(self.model, None)?;
+            let token = sampler.sample(&self.context, -1);
+            sampler.accept(token);
+            sequences.push(BatchSequence {
+                seq_id,
+                n_past,
+                sampler,
+                next: Some(token),
+                n_generated: 0,
+                output: String::new(),
+            });
+        }
+        loop {
+            self.batch.clear();
+            let mut active = Vec::new();
+            for (i, seq) in sequences.iter_mut().enumerate() {
+                let Some(token) = seq.next.take() else {
+                    continue;
+                };
+                let finished = self.model.is_eog_token(token)
+                    || self.stop_tokens.contains(&token)
+                    || options
+                        .max_new_tokens
+                        .is_some_and(|max| seq.n_generated >= max);
+                if finished {
+                    continue;
+                }
+                if seq.n_past >= seq_capacity {
+                    return Err(BindingsError::ContextFull {
+                        n_past: seq.n_past,
+                        ctx_size: self.seq_capacity(),
+                    }
+                    .into());
+                }
+                seq.output.push_str(&self.token_text(token)?);
+                self.batch.add(token, seq.n_past, &[seq.seq_id], true)?;
+                seq.n_past += 1;
+                seq.n_generated += 1;
+                active.push(i);
+            }
+            if active.is_empty() {
+                break;
+            }
+            self.decode_batch()?;
+            for (batch_index, &i) in (0..).zip(&active) {
+                let seq = &mut sequences[i];
+                let token = seq.sampler.sample(&self.context, batch_index);
+                seq.sampler.accept(token);
+                seq.next = Some(token);
+            }
+        }
+        let answers: Vec<String> = sequences.into_iter().map(|seq| seq.output).collect();
+        if let Some(transcript) = &self.transcript {
+            let parameters = serde_json::json!({
+                "sampling": options.sampling,
+                "max_new_tokens": options.max_new_tokens,
+            });
+            for ((prompt, images), answer) in group.iter().zip(&answers) {
+                let record = TranscriptRecord::new(
+                    "local",
+                    prompt,
+                    describe_paths(images),
+                    parameters.clone(),
+                );
+                transcript.log_output(record, answer);
+            }
+        }
+        Ok(answers)
+    }
+
+    /// Number of context positions `stream_chat` would use for this turn, images included,
+    /// without evaluating anything. Compare with `remaining_context` to see if it fits.
+    pub fn count_tokens(&self, prompt: &str, images: &[impl AsRef<Path>]) -> Result<usize> {
+        let bitmaps = self.load_bitmaps(images)?;
+        let input = MtmdInputText {
+            text: self.format_prompt(prompt, bitmaps.len())?,
+            add_special: true,
+            parse_special: true,
+        };
+        let bitmap_refs: Vec<&MtmdBitmap> = bitmaps.iter().collect();
+        Ok(self.mtmd().tokenize(input, &bitmap_refs)?.total_tokens())
+    }
+
+    /// Context positions still free in this session.
+    pub fn remaining_context(&self) -> usize {
+        let used = usize::try_from(self.n_past).unwrap_or(0);
+        (self.seq_capacity() as usize).saturating_sub(used)
+    }
 
-        Ok(Self { backend, model })
+    // Positions one sequence may use: the context is split evenly between the `parallel`
+    // sequences of `chat_batch`.
+    fn seq_capacity(&self) -> u32 {
+        self.context.n_ctx() / self.parallel
     }
 
-    pub fn new_session(&self) -> Result<Session<'_>> {
-        Session::new(&self.backend, &self.model)
+    /// Answers with exactly one of `labels` for the image, returning it with the model's
+    /// probability for that answer (the product of its token probabilities, each taken
+    /// over the tokens the label set still allows). Runs in a fresh context, so this resets
+    /// the session.
+    pub fn classify(&mut self, image: impl AsRef<Path>, labels: &[&str]) -> Result<(String, f32)> {
+        if labels.is_empty() {
+            return Err(eyre!("Classification needs at least one label"));
+        }
+        let mut grammar = LlamaSampler::grammar(self.model, &choice_grammar(labels), "root")
+            .map_err(|e| eyre!("Invalid label grammar: {e}"))?;
+        let prompt = classify_prompt(labels);
+        let bitmaps = self.load_bitmaps(&[image])?;
+        let formatted = self.format_prompt(&prompt, bitmaps.len())?;
+        self.reset();
+        self.prefill(formatted, &bitmaps, true, true)?;
+
+        let mut answer = String::new();
+        let mut probability = 1.0;
+        let max_steps = labels.iter().map(|l| l.len()).max().unwrap_or(0) + 1;
+        for _ in 0..max_steps {
+            let mut candidates = LlamaTokenDataArray::from_iter(self.context.candidates(), false);
+            candidates.apply_sampler(&grammar);
+            let allowed: Vec<_> = candidates
+                .data
+                .iter()
+                .filter(|c| c.logit().is_finite())
+                .collect();
+            let Some(best) = allowed
+                .iter()
+                .max_by(|a, b| a.logit().total_cmp(&b.logit()))
+            else {
+                break;
+            };
+            let total: f32 = allowed
+                .iter()
+                .map(|c| (c.logit() - best.logit()).exp())
+                .sum();
+            probability /= total;
+            let token = best.id();
+            if self.model.is_eog_token(token) {
+                break;
+            }
+            grammar.accept(token);
+            answer.push_str(&self.model.token_to_str(token, Special::Tokenize)?);
+            let finished = labels.contains(&answer.as_str())
+                && !labels
+                    .iter()
+                    .any(|l| l.len() > answer.len() && l.starts_with(answer.as_str()));
+            if finished {
+                break;
+            }
+            self.decode_token(token)?;
+        }
+        self.reset();
+        if !labels.contains(&answer.as_str()) {
+            return Err(eyre!("Model answer {answer:?} is not one of the labels"));
+        }
+        Ok((answer, probability))
     }
-}
 
-pub struct Session<'a> {
-    model: &'a LlamaModel,
-    context: LlamaContext<'a>,
-    mtmd_ctx: MtmdContext,
-    batch: LlamaBatch<'a>,
-    n_past: i32,
-}
+    /// Raw logits for the first token the model would generate for this turn, without
+    /// sampling. The vector has one entry per vocabulary token (`n_vocab`, ~150k for
+    /// Qwen3-VL, so roughly 600 KB per call). The turn is removed from the context again,
+    /// so the session's history is unchanged.
+    pub fn first_token_logits(
+        &mut self,
+        prompt: &str,
+        images: &[impl AsRef<Path>],
+    ) -> Result<Vec<f32>> {
+        let bitmaps = self.load_bitmaps(images)?;
+        let formatted = self.format_prompt(prompt, bitmaps.len())?;
+        let n_past = self.n_past;
+        self.prefill(formatted, &bitmaps, true, true)?;
+        let logits = self.context.get_logits().to_vec();
+        self.context
+            .clear_kv_cache_seq(Some(0), Some(u32::try_from(n_past)?), None)?;
+        self.n_past = n_past;
+        Ok(logits)
+    }
 
-impl<'a> Session<'a> {
-    fn new(backend: &'a LlamaBackend, model: &'a LlamaModel) -> Result<Self> {
-        let ctx_params = LlamaContextParams::default()
-            .with_n_ctx(NonZeroU32::new(CTX_SIZE))
-            .with_flash_attention_policy(llama_cpp_sys_2::LLAMA_FLASH_ATTN_TYPE_ENABLED)
-            .with_n_threads(8)
-            .with_n_batch(CTX_SIZE)
-            .with_n_ubatch(CTX_SIZE);
-        let context = model.new_context(backend, ctx_params)?;
-        let mtmd_params = MtmdContextParams {
-            use_gpu: true,
-            n_threads: 8,
-            media_marker: CString::new(mtmd::mtmd_default_marker().to_string())?,
-            ..Default::default()
+    // How a generated token shows up in the answer.
+    fn token_text(&self, token: LlamaToken) -> Result<String> {
+        let special = if self.show_special_tokens {
+            Special::Tokenize
+        } else {
+            Special::Plaintext
         };
-        let mtmd_ctx = MtmdContext::init_from_file(MMPROJ_PATH, model, &mtmd_params)?;
+        Ok(self.model.token_to_str(token, special)?)
+    }
 
-        Ok(Self {
-            model,
-            context,
-            mtmd_ctx,
-            batch: LlamaBatch::new(CTX_SIZE as usize, 1),
-            n_past: 0,
-        })
+    // Log-probability of `token` under the model's distribution for the next token (before
+    // any sampler stage), and the `top_k` most likely tokens.
+    fn token_logprobs(
+        &self,
+        token: LlamaToken,
+        top_k: usize,
+    ) -> Result<(f32, Vec<TokenAlternative>)> {
+        let logits = self.context.get_logits();
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+        let logprob = logits[usize::try_from(token.0)?] - log_sum;
+        let mut ids: Vec<usize> = (0..logits.len()).collect();
+        let by_logit = |a: &usize, b: &usize| logits[*b].total_cmp(&logits[*a]);
+        if top_k < ids.len() {
+            ids.select_nth_unstable_by(top_k, by_logit);
+            ids.truncate(top_k);
+        }
+        ids.sort_unstable_by(by_logit);
+        let alternatives = ids
+            .into_iter()
+            .map(|id| {
+                let token = LlamaToken(i32::try_from(id)?);
+                Ok(TokenAlternative {
+                    text: self.token_text(token)?,
+                    token_id: token.0,
+                    logprob: logits[id] - log_sum,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok((logprob, alternatives))
     }
 
-    pub fn reset(&mut self) {
-        self.context.clear_kv_cache();
-        self.n_past = 0;
+    fn decode_token(&mut self, token: LlamaToken) -> Result<()> {
+        self.batch.clear();
+        self.batch.add(token, self.n_past, &[0], true)?;
+        // Only advanced once the token is in the KV cache, so a failure leaves the session
+        // where it was.
+        self.decode_batch()?;
+        self.n_past += 1;
+        Ok(())
     }
 
-    pub fn chat(&mut self, prompt: &str, images: &[impl AsRef<Path>]) -> Result<String> {
-        self.stream_chat(prompt, images)?.collect()
+    fn decode_batch(&mut self) -> Result<()> {
+        (self.decode)(&mut self.context, &mut self.batch)
     }
 
-    pub fn stream_chat(
+    fn prefill(
         &mut self,
-        prompt: &str,
-        images: &[impl AsRef<Path>],
-    ) -> Result<ResponseStream<'a, '_>> {
-        let mut bitmaps = Vec::new();
-        for p in images {
-            let path_str = p.as_ref().to_str().ok_or_else(|| eyre!("Invalid path"))?;
-            bitmaps.push(MtmdBitmap::from_file(&self.mtmd_ctx, path_str)?);
+        text: String,
+        bitmaps: &[MtmdBitmap],
+        add_special: bool,
+        logits_last: bool,
+    ) -> Result<()> {
+        if bitmaps.is_empty() {
+            return self.prefill_text(&text, add_special, logits_last);
         }
-        let marker = mtmd::mtmd_default_marker().to_string();
-        let full_prompt = if !bitmaps.is_empty() && !prompt.contains(&marker) {
-            format!("{marker} {prompt}")
+        self.n_past = self.eval_prompt(text, bitmaps, add_special, self.n_past, 0, logits_last)?;
+        Ok(())
+    }
+
+    // Text-only turns skip mtmd: the prompt is tokenized and decoded in `n_batch` chunks.
+    fn prefill_text(&mut self, text: &str, add_special: bool, logits_last: bool) -> Result<()> {
+        let add_bos = if add_special {
+            AddBos::Always
         } else {
-            prompt.to_string()
+            AddBos::Never
         };
-        let messages = vec![LlamaChatMessage::new("user".to_string(), full_prompt)?];
-        let chat_template = self.model.chat_template(None)?;
-        let formatted = self
-            .model
-            .apply_chat_template(&chat_template, &messages, true)?;
+        let tokens = self.model.str_to_token(text, add_bos)?;
+        let capacity = self.seq_capacity() as usize;
+        let available = capacity.saturating_sub(usize::try_from(self.n_past)?);
+        if tokens.len() > available {
+            return Err(BindingsError::ContextOverflow {
+                needed: tokens.len(),
+                available,
+            }
+            .into());
+        }
+        let n_batch = usize::try_from(self.n_batch)?;
+        let last = tokens.len().saturating_sub(1);
+        for (chunk_index, chunk) in tokens.chunks(n_batch).enumerate() {
+            self.batch.clear();
+            let mut pos = self.n_past;
+            for (i, &token) in chunk.iter().enumerate() {
+                let logits = logits_last && chunk_index * n_batch + i == last;
+                self.batch.add(token, pos, &[0], logits)?;
+                pos += 1;
+            }
+            self.decode_batch()?;
+            self.n_past = pos;
+        }
+        Ok(())
+    }
+
+    // Time to prefill a text-only `prompt` into an empty context, through `prefill_text` or
+    // through mtmd's `eval_prompt`, for `MultimodalModel::benchmark_text_prefill`.
+    pub(crate) fn time_text_prefill(&mut self, prompt: &str, via_mtmd: bool) -> Result<Duration> {
+        self.reset();
+        let formatted = self.format_prompt(prompt, 0)?;
+        let start = Instant::now();
+        if via_mtmd {
+            self.n_past = self.eval_prompt(formatted, &[], true, 0, 0, true)?;
+        } else {
+            self.prefill_text(&formatted, true, true)?;
+        }
+        let elapsed = start.elapsed();
+        self.reset();
+        Ok(elapsed)
+    }
+
+    // Evaluates `text` into sequence `seq_id` starting at `n_past`; returns the new position.
+    fn eval_prompt(
+        &self,
+        text: String,
+        bitmaps: &[MtmdBitmap],
+        add_special: bool,
+        n_past: i32,
+        seq_id: i32,
+        logits_last: bool,
+    ) -> Result<i32> {
         let input = MtmdInputText {
-            text: formatted,
-            add_special: true,
+            text,
+            add_special,
             parse_special: true,
         };
         let bitmap_refs: Vec<&MtmdBitmap> = bitmaps.iter().collect();
-        let chunks = self.mtmd_ctx.tokenize(input, &bitmap_refs)?;
-        self.n_past =
-            chunks.eval_chunks(&self.mtmd_ctx, &self.context, self.n_past, 0, 4096, true)?;
-        let sampler = LlamaSampler::chain_simple([
-            LlamaSampler::penalties(-1, 1.0, 0.0, 1.5),
-            LlamaSampler::top_p(0.8, 1),
-            LlamaSampler::temp(0.7),
-            LlamaSampler::greedy(),
-        ]);
+        // Held through evaluation, which runs the image encoder.
+        let mtmd_ctx = self.mtmd();
+        let chunks = mtmd_ctx.tokenize(input, &bitmap_refs)?;
+        let capacity = self.seq_capacity() as usize;
+        let available = capacity.saturating_sub(usize::try_from(n_past)?);
+        let needed = chunks.total_tokens();
+        if needed > available {
+            return Err(BindingsError::ContextOverflow { needed, available }.into());
+        }
+        let n_past = chunks.eval_chunks(
+            &mtmd_ctx,
+            &self.context,
+            n_past,
+            seq_id,
+            self.n_batch,
+            logits_last,
+        )?;
+        drop(mtmd_ctx);
+        Ok(n_past)
+    }
 
-        Ok(ResponseStream {
-            session: self,
-            sampler,
-            is_done: false,
-        })
+    fn mtmd(&self) -> MutexGuard<'a, MtmdContext> {
+        self.mtmd_ctx.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // `eval_chunks` evaluates everything in one call, so to report progress the prompt is cut
+    // right after each media marker and every piece (one image each) is evaluated separately.
+    fn prefill_in_segments(
+        &mut self,
+        formatted: &str,
+        marker: &str,
+        bitmaps: &[MtmdBitmap],
+        on_prefill: &mut dyn FnMut(PrefillProgress),
+    ) -> Result<()> {
+        let mut segments = Vec::new();
+        let mut rest = formatted;
+        for _ in bitmaps {
+            let end = rest
+                .find(marker)
+                .ok_or_else(|| eyre!("Fewer media markers than images in prompt"))?
+                + marker.len();
+            segments.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+        if !rest.is_empty() {
+            segments.push(rest);
+        }
+        // The whole turn is checked up front, so a late segment can't overflow after the
+        // earlier ones are already in the KV cache.
+        let mut needed = 0;
+        for (i, segment) in segments.iter().enumerate() {
+            let bitmap = bitmaps.get(i..=i).unwrap_or_default();
+            needed += self.count_prompt_tokens(segment, bitmap, i == 0)?;
+        }
+        let capacity = self.seq_capacity() as usize;
+        let available = capacity.saturating_sub(usize::try_from(self.n_past)?);
+        if needed > available {
+            return Err(BindingsError::ContextOverflow { needed, available }.into());
+        }
+        let total_chunks = segments.len();
+        let start = self.n_past;
+        for (i, segment) in segments.into_iter().enumerate() {
+            on_prefill(PrefillProgress {
+                chunk: i + 1,
+                total_chunks,
+            });
+            let bitmap = bitmaps.get(i..=i).unwrap_or_default();
+            let result = self.prefill(segment.to_string(), bitmap, i == 0, i + 1 == total_chunks);
+            if let Err(e) = result {
+                // Earlier segments are already in the KV cache; drop them so a failure
+                // leaves the session where it was, like a single `prefill`.
+                self.context
+                    .clear_kv_cache_seq(Some(0), Some(u32::try_from(start)?), None)?;
+                self.n_past = start;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Positions `prefill` would take for `text` and `bitmaps`, without evaluating anything.
+    fn count_prompt_tokens(
+        &self,
+        text: &str,
+        bitmaps: &[MtmdBitmap],
+        add_special: bool,
+    ) -> Result<usize> {
+        if bitmaps.is_empty() {
+            let add_bos = if add_special {
+                AddBos::Always
+            } else {
+                AddBos::Never
+            };
+            return Ok(self.model.str_to_token(text, add_bos)?.len());
+        }
+        let input = MtmdInputText {
+            text: text.to_string(),
+            add_special,
+            parse_special: true,
+        };
+        let bitmap_refs: Vec<&MtmdBitmap> = bitmaps.iter().collect();
+        Ok(self.mtmd().tokenize(input, &bitmap_refs)?.total_tokens())
     }
 }
 
@@ -145,37 +1988,373 @@ pub struct ResponseStream<'a, 'b> {
     session: &'b mut Session<'a>,
     sampler: LlamaSampler,
     is_done: bool,
+    max_new_tokens: Option<usize>,
+    n_generated: usize,
+    stop: Vec<String>,
+    pending: String,
+    transcript: Option<TranscriptRecord>,
+    output: String,
+    starts_in_reasoning: bool,
+    // Assistant prefix, yielded before the first generated piece.
+    prefix: Option<String>,
+    prompt_eval: Duration,
+    generation: Duration,
+}
+
+/// Timings of one local answer, from [`ResponseStream::stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenerationStats {
+    /// Evaluating the prompt and images (`eval_chunks`).
+    pub prompt_eval: Duration,
+    /// Sampling and decoding the answer, so far.
+    pub generation: Duration,
+    pub tokens: usize,
+    pub tokens_per_sec: f64,
+}
+
+const THINK_OPEN: &str = "<think>";
+const THINK_CLOSE: &str = "</think>";
+
+/// A piece of a local answer, split like the API's `ChatEvent`s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    Content(String),
+    Reasoning(String),
+}
+
+/// Splits a [`ResponseStream`] into reasoning (`<think>...</think>`) and content, handling
+/// tags split across pieces. Created with [`ResponseStream::with_reasoning`].
+pub struct ReasoningStream<'a, 'b> {
+    inner: ResponseStream<'a, 'b>,
+    in_reasoning: bool,
+    // Right after `</think>` the model emits blank lines before the answer; drop them.
+    trim_content: bool,
+    buffer: String,
+    ready: VecDeque<StreamEvent>,
+}
+
+impl<'a, 'b> ResponseStream<'a, 'b> {
+    /// Throughput so far; complete once the stream returned `None`.
+    pub fn stats(&self) -> GenerationStats {
+        #[allow(clippy::cast_precision_loss)]
+        let tokens_per_sec = if self.generation.is_zero() {
+            0.0
+        } else {
+            self.n_generated as f64 / self.generation.as_secs_f64()
+        };
+        GenerationStats {
+            prompt_eval: self.prompt_eval,
+            generation: self.generation,
+            tokens: self.n_generated,
+            tokens_per_sec,
+        }
+    }
+
+    pub const fn with_reasoning(self) -> ReasoningStream<'a, 'b> {
+        ReasoningStream {
+            in_reasoning: self.starts_in_reasoning,
+            inner: self,
+            trim_content: false,
+            buffer: String::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Yields each generated token with its log-probability and the `top_k` most likely
+    /// alternatives, e.g. to only trust confident extractions. Stop strings and the
+    /// assistant prefix aren't applied, since they don't line up with tokens.
+    pub const fn with_logprobs(self, top_k: usize) -> LogprobStream<'a, 'b> {
+        LogprobStream { inner: self, top_k }
+    }
+}
+
+/// A generated token, from [`ResponseStream::with_logprobs`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenLogprob {
+    pub text: String,
+    pub token_id: i32,
+    /// Natural log of the token's probability, before sampling settings like temperature.
+    pub logprob: f32,
+    /// The most likely tokens at this position, most likely first.
+    pub top_k_alternatives: Vec<TokenAlternative>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenAlternative {
+    pub text: String,
+    pub token_id: i32,
+    pub logprob: f32,
+}
+
+pub struct LogprobStream<'a, 'b> {
+    inner: ResponseStream<'a, 'b>,
+    top_k: usize,
+}
+
+impl LogprobStream<'_, '_> {
+    pub fn stats(&self) -> GenerationStats {
+        self.inner.stats()
+    }
+}
+
+impl Iterator for LogprobStream<'_, '_> {
+    type Item = Result<TokenLogprob>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let top_k = self.top_k;
+        let start = Instant::now();
+        let next = self.inner.next_token(|session, token| {
+            let (logprob, alternatives) = session.token_logprobs(token, top_k)?;
+            Ok((token.0, logprob, alternatives))
+        });
+        self.inner.generation += start.elapsed();
+        match next {
+            Some(Ok((text, (token_id, logprob, top_k_alternatives)))) => {
+                if self.inner.transcript.is_some() {
+                    self.inner.output.push_str(&text);
+                }
+                Some(Ok(TokenLogprob {
+                    text,
+                    token_id,
+                    logprob,
+                    top_k_alternatives,
+                }))
+            }
+            Some(Err(e)) => Some(Err(e)),
+            None => {
+                self.inner.log_transcript();
+                None
+            }
+        }
+    }
+}
+
+impl FusedIterator for LogprobStream<'_, '_> {}
+
+impl ReasoningStream<'_, '_> {
+    pub fn stats(&self) -> GenerationStats {
+        self.inner.stats()
+    }
+
+    fn emit(&mut self, text: &str) {
+        let text = if !self.in_reasoning && self.trim_content {
+            text.trim_start()
+        } else {
+            text
+        };
+        if text.is_empty() {
+            return;
+        }
+        if self.in_reasoning {
+            self.ready
+                .push_back(StreamEvent::Reasoning(text.to_string()));
+        } else {
+            self.trim_content = false;
+            self.ready.push_back(StreamEvent::Content(text.to_string()));
+        }
+    }
+
+    fn split(&mut self) {
+        loop {
+            let tag = if self.in_reasoning {
+                THINK_CLOSE
+            } else {
+                THINK_OPEN
+            };
+            let Some(at) = self.buffer.find(tag) else {
+                break;
+            };
+            let before: String = self.buffer.drain(..at + tag.len()).collect();
+            self.emit(&before[..at]);
+            self.in_reasoning = !self.in_reasoning;
+            self.trim_content = !self.in_reasoning;
+        }
+        let tag = if self.in_reasoning {
+            THINK_CLOSE
+        } else {
+            THINK_OPEN
+        };
+        let emit = self.buffer.len() - held_back_len(&self.buffer, &[tag]);
+        let text: String = self.buffer.drain(..emit).collect();
+        self.emit(&text);
+    }
+}
+
+impl Iterator for ReasoningStream<'_, '_> {
+    type Item = Result<StreamEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.ready.pop_front() {
+                return Some(Ok(event));
+            }
+            match self.inner.next() {
+                Some(Ok(piece)) => {
+                    self.buffer.push_str(&piece);
+                    self.split();
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    let rest = std::mem::take(&mut self.buffer);
+                    self.emit(&rest);
+                    return self.ready.pop_front().map(Ok);
+                }
+            }
+        }
+    }
+}
+
+impl FusedIterator for ReasoningStream<'_, '_> {}
+
+fn describe_paths(paths: &[impl AsRef<Path>]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|p| p.as_ref().display().to_string())
+        .collect()
+}
+
+// `prompt` after one "Image i: <marker>" line per image, so it can refer to them by number.
+fn labelled_prompt(prompt: &str, n_images: usize, marker: &str) -> String {
+    let mut labelled = String::new();
+    for i in 1..=n_images {
+        let _ = writeln!(labelled, "Image {i}: {marker}");
+    }
+    let _ = write!(labelled, "\n{prompt}");
+    labelled
+}
+
+// Length of the longest suffix of `text` that could still grow into one of `stops`.
+fn held_back_len(text: &str, stops: &[impl AsRef<str>]) -> usize {
+    let longest = stops.iter().map(|s| s.as_ref().len()).max().unwrap_or(0);
+    (1..longest.min(text.len() + 1))
+        .rev()
+        .map(|k| text.len() - k)
+        .filter(|&start| text.is_char_boundary(start))
+        .find(|&start| {
+            stops
+                .iter()
+                .any(|stop| stop.as_ref().starts_with(&text[start..]))
+        })
+        .map_or(0, |start| text.len() - start)
 }
 
 impl Iterator for ResponseStream<'_, '_> {
     type Item = Result<String>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let next = if let Some(prefix) = self.prefix.take() {
+            Some(Ok(prefix))
+        } else {
+            let start = Instant::now();
+            let next = self.next_stopped();
+            self.generation += start.elapsed();
+            next
+        };
+        match &next {
+            Some(Ok(piece)) if self.transcript.is_some() => self.output.push_str(piece),
+            None => self.log_transcript(),
+            _ => {}
+        }
+        next
+    }
+}
+
+// Once done, `next_piece` keeps returning `None` and nothing is left pending.
+impl FusedIterator for ResponseStream<'_, '_> {}
+
+impl ResponseStream<'_, '_> {
+    fn log_transcript(&mut self) {
+        if let (Some(logger), Some(record)) = (&self.session.transcript, self.transcript.take()) {
+            logger.log_output(record, &self.output);
+        }
+    }
+
+    fn next_stopped(&mut self) -> Option<Result<String>> {
+        if self.stop.is_empty() {
+            return self.next_piece();
+        }
+        loop {
+            match self.next_piece() {
+                Some(Ok(piece)) => {
+                    self.pending.push_str(&piece);
+                    let stop_at = self
+                        .stop
+                        .iter()
+                        .filter_map(|stop| self.pending.find(stop.as_str()))
+                        .min();
+                    if let Some(stop_at) = stop_at {
+                        self.is_done = true;
+                        self.pending.truncate(stop_at);
+                        let out = std::mem::take(&mut self.pending);
+                        return (!out.is_empty()).then_some(Ok(out));
+                    }
+                    let emit = self.pending.len() - held_back_len(&self.pending, &self.stop);
+                    if emit > 0 {
+                        return Some(Ok(self.pending.drain(..emit).collect()));
+                    }
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    let out = std::mem::take(&mut self.pending);
+                    return (!out.is_empty()).then_some(Ok(out));
+                }
+            }
+        }
+    }
+
+    fn next_piece(&mut self) -> Option<Result<String>> {
+        self.next_token(|_, _| Ok(()))
+            .map(|piece| piece.map(|(text, ())| text))
+    }
+
+    // Samples and decodes one token. `inspect` sees the session before the token is
+    // decoded, while the logits it was sampled from are still there.
+    fn next_token<T>(
+        &mut self,
+        inspect: impl FnOnce(&Session, LlamaToken) -> Result<T>,
+    ) -> Option<Result<(String, T)>> {
         if self.is_done {
             return None;
         }
+        if self
+            .max_new_tokens
+            .is_some_and(|max| self.n_generated >= max)
+        {
+            self.is_done = true;
+            return None;
+        }
+        let ctx_size = self.session.seq_capacity();
+        if u32::try_from(self.session.n_past).is_ok_and(|n_past| n_past >= ctx_size) {
+            self.is_done = true;
+            return Some(Err(BindingsError::ContextFull {
+                n_past: self.session.n_past,
+                ctx_size,
+            }
+            .into()));
+        }
         let token = self.sampler.sample(&self.session.context, -1);
         self.sampler.accept(token);
-        if self.session.model.is_eog_token(token) {
+        if self.session.model.is_eog_token(token) || self.session.stop_tokens.contains(&token) {
             self.is_done = true;
             return None;
         }
-        let piece = match self.session.model.token_to_str(token, Special::Tokenize) {
-            Ok(s) => s,
-            Err(e) => return Some(Err(eyre!(e))),
-        };
-        self.session.batch.clear();
-        if let Err(e) = self
+        // After a failed token the context can't be trusted, so the stream ends with the error.
+        let piece = match self
             .session
-            .batch
-            .add(token, self.session.n_past, &[0], true)
+            .token_text(token)
+            .and_then(|text| Ok((text, inspect(self.session, token)?)))
         {
-            return Some(Err(eyre!(e)));
-        }
-        self.session.n_past += 1;
-        if let Err(e) = self.session.context.decode(&mut self.session.batch) {
-            return Some(Err(eyre!("Decode failed: {e}")));
+            Ok(piece) => piece,
+            Err(e) => {
+                self.is_done = true;
+                return Some(Err(e));
+            }
+        };
+        if let Err(e) = self.session.decode_token(token) {
+            self.is_done = true;
+            return Some(Err(e));
         }
+        self.n_generated += 1;
 
         Some(Ok(piece))
     }
@@ -188,17 +2367,10 @@ pub fn run() -> Result<()> {
     let img_island = Path::new("assets/img/island.png");
     let img_farm = Path::new("assets/img/farm.png");
     let img_torus = Path::new("assets/img/torus.png");
-    let prompt = "Caption this image in one paragraph. Respond with the caption only.";
+    let prompt = CAPTION_PROMPT;
 
-    let now = Instant::now();
-
-    // Warmup
     info!("Island: {}", session.chat(prompt, &[img_island])?);
     session.reset();
-
-    let now2 = Instant::now();
-
-    // Actual requests
     info!("Farm: {}", session.chat(prompt, &[img_farm])?);
     session.reset();
     info!("Torus: {}", session.chat(prompt, &[img_torus])?);
@@ -214,8 +2386,419 @@ pub fn run() -> Result<()> {
             .chat("What are the similarities with this picture?", &[img_torus])?
     );
 
-    info!("Total time for [bindings]: {:?}", now.elapsed());
-    info!("Total time for [bindings] (excluding warmup): {:?}", now2.elapsed());
+    drop(session);
+
+    let report = model_manager.benchmark(&BenchConfig::default())?;
+    info!("Benchmark [bindings]:\n{report}");
+    let prefill = model_manager.benchmark_text_prefill(&CAPTION_PROMPT.repeat(40), 5)?;
+    info!("Text prefill [bindings]:\n{prefill}");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_IMAGES: &[&Path] = &[];
+
+    fn failing_decode(_: &mut LlamaContext<'_>, _: &mut LlamaBatch<'_>) -> Result<()> {
+        Err(eyre!("Decode failed: injected by test"))
+    }
+
+    // Loaded once and shared, since loading takes seconds.
+    fn model() -> &'static MultimodalModel {
+        static MODEL: OnceLock<MultimodalModel> = OnceLock::new();
+        MODEL.get_or_init(|| MultimodalModel::load().expect("model files in assets/qwen3vl"))
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn failed_prefill_leaves_session_usable() {
+        let mut session = model().new_session().unwrap();
+        session.decode = failing_decode;
+        assert!(session.chat("Say hi.", NO_IMAGES).is_err());
+        assert_eq!(session.n_past, 0);
+        session.decode = llama_decode;
+        assert!(!session.chat("Say hi.", NO_IMAGES).unwrap().is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn segmented_prefill_checks_the_whole_turn_first() {
+        let mut session = model().new_session().unwrap();
+        let island = Path::new("assets/img/island.png");
+        // The image segment fits on its own; the text after it doesn't.
+        let prompt = format!(
+            "Describe it. {}",
+            "word ".repeat(model().ctx_size() as usize)
+        );
+        let mut progress = Vec::new();
+        let err = session
+            .chat_with(&prompt)
+            .images(&[island])
+            .on_prefill(&mut |p| progress.push(p))
+            .call()
+            .unwrap_err();
+        let Some(&BindingsError::ContextOverflow { needed, available }) = err.downcast_ref() else {
+            panic!("expected ContextOverflow, got {err}");
+        };
+        assert!(needed > model().ctx_size() as usize);
+        assert_eq!(available, model().ctx_size() as usize);
+        assert!(progress.is_empty());
+        assert_eq!(session.n_past, 0);
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn failed_segment_rolls_back_the_turn() {
+        let mut session = model().new_session().unwrap();
+        let island = Path::new("assets/img/island.png");
+        // The image segment goes through mtmd; the trailing text decodes through the seam.
+        session.decode = failing_decode;
+        let mut progress = Vec::new();
+        let result = session
+            .chat_with("Describe it.")
+            .images(&[island])
+            .on_prefill(&mut |p| progress.push(p))
+            .call();
+        assert!(result.is_err());
+        assert_eq!(progress.len(), 2);
+        assert_eq!(session.n_past, 0);
+
+        // Greedy, so leftover image positions in the KV cache would change the answer.
+        session.decode = llama_decode;
+        let answer = session.chat("Say hi.", NO_IMAGES).unwrap();
+        let fresh = model()
+            .new_session()
+            .unwrap()
+            .chat("Say hi.", NO_IMAGES)
+            .unwrap();
+        assert_eq!(answer, fresh);
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn failed_decode_leaves_session_usable() {
+        let mut session = model().new_session().unwrap();
+        let mut stream = session.stream_chat("Count to ten.", NO_IMAGES).unwrap();
+        assert!(stream.next().unwrap().is_ok());
+        let n_past = stream.session.n_past;
+        stream.session.decode = failing_decode;
+        assert!(stream.next().unwrap().is_err());
+        assert_eq!(stream.session.n_past, n_past);
+        drop(stream);
+        session.decode = llama_decode;
+
+        session.reset();
+        assert_eq!(session.n_past, 0);
+        assert!(!session.chat("Say hi.", NO_IMAGES).unwrap().is_empty());
+    }
+
+    fn stage_names(params: &SamplingParams) -> Vec<&'static str> {
+        params
+            .default_chain()
+            .iter()
+            .map(|stage| match stage {
+                SamplerStage::Penalties { .. } => "penalties",
+                SamplerStage::TopK(_) => "top_k",
+                SamplerStage::TopP(_) => "top_p",
+                SamplerStage::MinP(_) => "min_p",
+                SamplerStage::Temperature(_) => "temperature",
+                SamplerStage::Greedy => "greedy",
+                SamplerStage::Dist => "dist",
+                _ => "other",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn default_chain_is_greedy_without_seed() {
+        let params = SamplingParams::default();
+        assert_eq!(stage_names(&params), ["penalties", "greedy"]);
+    }
+
+    #[test]
+    fn default_chain_samples_with_seed() {
+        let params = SamplingParams {
+            seed: Some(7),
+            top_k: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(
+            stage_names(&params),
+            ["penalties", "top_k", "top_p", "temperature", "dist"]
+        );
+    }
+
+    #[test]
+    fn default_chain_samples_when_not_greedy() {
+        let params = SamplingParams {
+            greedy: Some(false),
+            temperature: Some(1.2),
+            ..Default::default()
+        };
+        let chain = params.default_chain();
+        assert_eq!(chain.last(), Some(&SamplerStage::Dist));
+        assert!(chain.contains(&SamplerStage::Temperature(1.2)));
+    }
+
+    fn sampled_answer(sampling: SamplingParams) -> String {
+        let mut session = model().new_session().unwrap();
+        session
+            .chat_with("Write a four line poem about the sea.")
+            .sampling(sampling)
+            .max_new_tokens(48)
+            .call()
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn temperature_changes_sampled_output() {
+        let at = |temperature| SamplingParams {
+            greedy: Some(false),
+            seed: Some(42),
+            temperature: Some(temperature),
+            ..Default::default()
+        };
+        assert_ne!(sampled_answer(at(0.1)), sampled_answer(at(1.5)));
+    }
+
+    #[test]
+    fn seed_makes_dist_the_terminal_stage() {
+        let params = SamplingParams {
+            seed: Some(1234),
+            ..Default::default()
+        };
+        assert_eq!(params.default_chain().last(), Some(&SamplerStage::Dist));
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn fixed_seed_is_deterministic() {
+        let seeded = || SamplingParams {
+            seed: Some(1234),
+            temperature: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(sampled_answer(seeded()), sampled_answer(seeded()));
+    }
+
+    #[test]
+    fn labelled_prompt_numbers_images_in_order() {
+        let prompt = labelled_prompt("What changed?", 3, "<m>");
+        assert_eq!(
+            prompt,
+            "Image 1: <m>\nImage 2: <m>\nImage 3: <m>\n\nWhat changed?"
+        );
+        assert_eq!(prompt.matches("<m>").count(), 3);
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn session_ctx_size_overrides_the_model() {
+        let ctx_size = model().ctx_size() / 2;
+        let session = Session::with_model(model())
+            .ctx_size(ctx_size)
+            .build()
+            .unwrap();
+        assert_eq!(session.context.n_ctx(), ctx_size);
+        assert_eq!(session.remaining_context(), ctx_size as usize);
+    }
+
+    #[test]
+    fn state_header_is_keyed_on_metadata_not_path() {
+        let header = StateHeader {
+            model: ModelFingerprint {
+                arch: "qwen3vl".to_string(),
+                n_vocab: 151_936,
+                n_embd: 2560,
+                n_ctx_train: 262_144,
+            },
+            ctx_size: 4096,
+            n_past: 12,
+        };
+        let json = serde_json::to_string(&header).unwrap();
+        assert!(!json.contains("path"));
+        let parsed: StateHeader = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.model, header.model);
+        assert_eq!(
+            parsed.model.to_string(),
+            "qwen3vl (n_vocab 151936, n_embd 2560, n_ctx_train 262144)"
+        );
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn state_loads_into_model_opened_by_absolute_path() {
+        let state = std::env::temp_dir().join("qwen-llm-rs-state-test.bin");
+        let mut session = model().new_session().unwrap();
+        session.chat("Remember the word apple.", NO_IMAGES).unwrap();
+        session.save_state(&state).unwrap();
+
+        let absolute = MultimodalModel::builder()
+            .model_path(fs::canonicalize(MODEL_PATH).unwrap())
+            .mmproj_path(fs::canonicalize(MMPROJ_PATH).unwrap())
+            .load()
+            .unwrap();
+        let mut restored = absolute.new_session().unwrap();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.n_past, session.n_past);
+        fs::remove_file(state).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn compare_sees_both_images() {
+        let mut session = model().new_session().unwrap();
+        let island = Path::new("assets/img/island.png");
+        let farm = Path::new("assets/img/farm.png");
+        let answer: String = session
+            .compare("Describe each image in one sentence.", &[island, farm])
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert!(!answer.trim().is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn parallel_sessions_use_one_slot() {
+        let session = Session::with_model(model()).parallel(2).build().unwrap();
+        assert_eq!(session.remaining_context(), model().ctx_size() as usize / 2);
+    }
+
+    fn assert_fused<T>(stream: &mut impl Iterator<Item = Result<T>>) {
+        for _ in 0..3 {
+            assert!(stream.next().is_none());
+        }
+    }
+
+    fn drain<T>(stream: &mut impl Iterator<Item = Result<T>>) {
+        for item in stream {
+            item.unwrap();
+        }
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn streams_stay_done_after_end() {
+        let mut session = Session::with_model(model())
+            .max_new_tokens(4)
+            .build()
+            .unwrap();
+        let mut stream = session.stream_chat("Count to ten.", NO_IMAGES).unwrap();
+        drain(&mut stream);
+        assert_fused(&mut stream);
+
+        let mut stream = session
+            .stream_chat("Count to ten.", NO_IMAGES)
+            .unwrap()
+            .with_reasoning();
+        drain(&mut stream);
+        assert_fused(&mut stream);
+
+        let mut stream = session
+            .stream_chat("Count to ten.", NO_IMAGES)
+            .unwrap()
+            .with_logprobs(3);
+        drain(&mut stream);
+        assert_fused(&mut stream);
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn streams_stay_done_after_error() {
+        let mut session = model().new_session().unwrap();
+        let mut stream = session.stream_chat("Count to ten.", NO_IMAGES).unwrap();
+        stream.session.decode = failing_decode;
+        assert!(stream.next().unwrap().is_err());
+        assert_fused(&mut stream);
+
+        session.reset();
+        session.decode = llama_decode;
+        let mut stream = session
+            .stream_chat("Count to ten.", NO_IMAGES)
+            .unwrap()
+            .with_reasoning();
+        stream.inner.session.decode = failing_decode;
+        assert!(stream.next().unwrap().is_err());
+        assert_fused(&mut stream);
+
+        session.reset();
+        session.decode = llama_decode;
+        let mut stream = session
+            .stream_chat("Count to ten.", NO_IMAGES)
+            .unwrap()
+            .with_logprobs(3);
+        stream.inner.session.decode = failing_decode;
+        assert!(stream.next().unwrap().is_err());
+        assert_fused(&mut stream);
+    }
+
+    // An `AsyncSession` whose worker has already exited.
+    fn stopped_session() -> AsyncSession {
+        let (commands, _) = mpsc::unbounded_channel();
+        AsyncSession { commands }
+    }
+
+    #[test]
+    fn shared_stream_reports_a_stopped_worker() {
+        let (handle, shared) = stopped_session().stream_into_shared("Say hi.", NO_IMAGES);
+        let err = handle.join().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Session worker has stopped");
+        assert!(shared.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn shared_stream_keeps_history() {
+        let model = Arc::new(MultimodalModel::load().unwrap());
+        let session = model.async_session();
+        let (handle, _) = session.stream_into_shared("My name is Ada. Reply with OK.", NO_IMAGES);
+        handle.join().unwrap().unwrap();
+        let (handle, answer) = session.stream_into_shared("What is my name?", NO_IMAGES);
+        handle.join().unwrap().unwrap();
+        assert!(answer.lock().unwrap().contains("Ada"));
+    }
+
+    #[test]
+    fn background_stream_reports_a_stopped_worker() {
+        let mut stream = stopped_session().stream_in_background("Say hi.", NO_IMAGES);
+        let err = stream.next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Session worker has stopped");
+        assert_fused(&mut stream);
+    }
+
+    #[test]
+    #[ignore = "needs the model files in assets/qwen3vl"]
+    fn background_stream_keeps_history() {
+        let model = Arc::new(MultimodalModel::load().unwrap());
+        let session = model.async_session();
+        let first = session.stream_in_background("My name is Ada. Reply with OK.", NO_IMAGES);
+        first.collect::<Result<String>>().unwrap();
+        let second = session.stream_in_background("What is my name?", NO_IMAGES);
+        let mut answer = String::new();
+        loop {
+            match second.next_timeout(Duration::from_millis(50)) {
+                Poll::Ready(Some(piece)) => answer.push_str(&piece.unwrap()),
+                Poll::Ready(None) => break,
+                Poll::Pending => {}
+            }
+        }
+        assert!(answer.contains("Ada"));
+    }
+
+    #[test]
+    fn background_stream_stays_done() {
+        let (tx, pieces) = std_mpsc::sync_channel(4);
+        tx.send(Ok("a".to_string())).unwrap();
+        tx.send(Err(eyre!("failed"))).unwrap();
+        drop(tx);
+        let mut stream = BackgroundStream { pieces };
+        assert_eq!(stream.next().unwrap().unwrap(), "a");
+        assert!(stream.next().unwrap().is_err());
+        assert_fused(&mut stream);
+    }
+}
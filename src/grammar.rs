@@ -0,0 +1,138 @@
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("Unsupported JSON schema: {0}")]
+pub struct UnsupportedSchema(String);
+
+/// GBNF grammar that only accepts exactly one of `choices`.
+pub fn choice_grammar(choices: &[&str]) -> String {
+    let alternatives = choices
+        .iter()
+        .map(|choice| gbnf_literal(choice))
+        .collect::<Vec<_>>()
+        .join(" | ");
+    format!("root ::= {alternatives}")
+}
+
+const JSON_PRIMITIVES: &str = r#"ws ::= [ \t\n]*
+string ::= "\"" ( [^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] ) )* "\"" ws
+integer ::= "-"? ( [0-9] | [1-9] [0-9]* ) ws
+number ::= "-"? ( [0-9] | [1-9] [0-9]* ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )? ws
+boolean ::= ( "true" | "false" ) ws
+null ::= "null" ws"#;
+
+/// Converts a JSON schema into a GBNF grammar for JSON documents matching it.
+///
+/// Supports `type` (including lists of types), `properties`, `items`, `enum`, `const`,
+/// `anyOf` and `oneOf`. Object properties are generated in key order and are all
+/// required; `$ref` and open-ended objects are rejected.
+pub fn json_schema_grammar(schema: &Value) -> Result<String, UnsupportedSchema> {
+    let root = schema_rule(schema)?;
+    Ok(format!("root ::= ws {root}\n{JSON_PRIMITIVES}"))
+}
+
+fn schema_rule(schema: &Value) -> Result<String, UnsupportedSchema> {
+    let unsupported = |what: &str| UnsupportedSchema(format!("{what} in {schema}"));
+    let Value::Object(schema) = schema else {
+        return Err(unsupported("non-object schema"));
+    };
+    if schema.contains_key("$ref") {
+        return Err(unsupported("$ref"));
+    }
+    if let Some(value) = schema.get("const") {
+        return Ok(json_literal(value));
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        return Ok(alternatives(values.iter().map(json_literal)));
+    }
+    if let Some(options) = schema
+        .get("anyOf")
+        .or_else(|| schema.get("oneOf"))
+        .and_then(Value::as_array)
+    {
+        let rules = options
+            .iter()
+            .map(schema_rule)
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(alternatives(rules.into_iter()));
+    }
+    match schema.get("type") {
+        Some(Value::Array(types)) => {
+            let rules = types
+                .iter()
+                .map(|t| {
+                    let mut single = schema.clone();
+                    single.insert("type".to_string(), t.clone());
+                    schema_rule(&Value::Object(single))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(alternatives(rules.into_iter()))
+        }
+        Some(Value::String(t)) => match t.as_str() {
+            "string" | "integer" | "number" | "boolean" | "null" => Ok(t.clone()),
+            "array" => {
+                let item = schema
+                    .get("items")
+                    .ok_or_else(|| unsupported("array without items"))
+                    .and_then(schema_rule)?;
+                Ok(format!(
+                    r#"( "[" ws ( {item} ( "," ws {item} )* )? "]" ws )"#
+                ))
+            }
+            "object" => {
+                let properties = schema
+                    .get("properties")
+                    .and_then(Value::as_object)
+                    .ok_or_else(|| unsupported("object without properties"))?;
+                let members = properties
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok(format!(
+                            r#"{} ws ":" ws {}"#,
+                            gbnf_literal(&Value::String(key.clone()).to_string()),
+                            schema_rule(value)?
+                        ))
+                    })
+                    .collect::<Result<Vec<_>, UnsupportedSchema>>()?;
+                Ok(format!(
+                    r#"( "{{" ws {} "}}" ws )"#,
+                    members.join(r#" "," ws "#)
+                ))
+            }
+            other => Err(unsupported(&format!("type {other:?}"))),
+        },
+        _ => Err(unsupported("missing type")),
+    }
+}
+
+fn alternatives(rules: impl Iterator<Item = String>) -> String {
+    format!("( {} )", rules.collect::<Vec<_>>().join(" | "))
+}
+
+fn json_literal(value: &Value) -> String {
+    format!("{} ws", gbnf_literal(&value.to_string()))
+}
+
+fn gbnf_literal(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+pub(crate) fn classify_prompt(labels: &[&str]) -> String {
+    format!(
+        "Classify this image. Answer with exactly one of: {}.",
+        labels.join(", ")
+    )
+}
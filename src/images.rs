@@ -0,0 +1,214 @@
+use base64::{engine::general_purpose, Engine as _};
+use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageError, ImageFormat, ImageReader, ImageResult};
+use std::borrow::Cow;
+use std::future::Future;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::fs;
+
+pub type LoadedImage = (Vec<u8>, Option<String>);
+
+/// An image given as a file path, already-read encoded bytes (PNG, JPEG, ...) or a URL.
+/// The format is sniffed from the content, so callers don't need to specify it.
+#[derive(Debug, Clone, Copy)]
+pub enum ImageSource<'a> {
+    Path(&'a Path),
+    Bytes(&'a [u8]),
+    /// A `data:<mime>;base64,<data>` URL, as the API backend sends images.
+    DataUrl(&'a str),
+    /// An `http(s)://` URL, downloaded (blocking) when the image is loaded.
+    #[cfg(feature = "remote-images")]
+    Url(&'a str),
+}
+
+impl<'a> ImageSource<'a> {
+    /// The encoded image bytes: read from disk, decoded from the data URL or downloaded.
+    pub fn read(&self) -> io::Result<Cow<'a, [u8]>> {
+        Ok(match *self {
+            Self::Path(path) => Cow::Owned(std::fs::read(path)?),
+            Self::Bytes(bytes) => Cow::Borrowed(bytes),
+            Self::DataUrl(url) => Cow::Owned(decode_data_url(url)?),
+            #[cfg(feature = "remote-images")]
+            Self::Url(url) => Cow::Owned(download(url)?),
+        })
+    }
+
+    // How the image is named in transcripts: the path or URL, or a content hash for raw
+    // bytes and data URLs.
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Self::Path(path) => path.display().to_string(),
+            Self::Bytes(bytes) => format!("fnv1a:{:016x}", fnv1a(bytes)),
+            Self::DataUrl(url) => format!("fnv1a:{:016x}", fnv1a(url.as_bytes())),
+            #[cfg(feature = "remote-images")]
+            Self::Url(url) => (*url).to_string(),
+        }
+    }
+}
+
+fn decode_data_url(url: &str) -> io::Result<Vec<u8>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let (header, data) = url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(','))
+        .ok_or_else(|| invalid("Not a data URL"))?;
+    if !header.ends_with(";base64") {
+        return Err(invalid("Only base64 data URLs are supported"));
+    }
+    general_purpose::STANDARD
+        .decode(data.trim())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(feature = "remote-images")]
+fn download(url: &str) -> io::Result<Vec<u8>> {
+    let response = reqwest::blocking::get(url)
+        .and_then(reqwest::blocking::Response::error_for_status)
+        .map_err(io::Error::other)?;
+    Ok(response.bytes().map_err(io::Error::other)?.to_vec())
+}
+
+// FNV-1a rather than `DefaultHasher`, whose algorithm may change between Rust releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Formats llama.cpp decodes itself (with `stb_image`), in both backends: JPEG, PNG, BMP and
+/// GIF (first frame only).
+const NATIVE_MIME_TYPES: [&str; 4] = ["image/jpeg", "image/png", "image/bmp", "image/gif"];
+
+/// `bytes` in a format llama.cpp can decode, with its MIME type. WebP, which it can't, is
+/// converted to PNG; anything else is an error. The type is sniffed from the content, and
+/// `declared` is only used when that fails.
+pub(crate) fn normalize<'a>(
+    bytes: &'a [u8],
+    declared: Option<&str>,
+) -> ImageResult<(Cow<'a, [u8]>, &'static str)> {
+    let mime_type = infer::get(bytes).map(|kind| kind.mime_type()).or(declared);
+    if let Some(native) = NATIVE_MIME_TYPES
+        .into_iter()
+        .find(|&native| Some(native) == mime_type)
+    {
+        return Ok((Cow::Borrowed(bytes), native));
+    }
+    if mime_type == Some("image/webp") {
+        let image = image::load_from_memory_with_format(bytes, ImageFormat::WebP)?;
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        return Ok((Cow::Owned(png), "image/png"));
+    }
+    let hint = ImageFormatHint::Name(mime_type.unwrap_or("unknown format").to_string());
+    Err(ImageError::Unsupported(
+        UnsupportedError::from_format_and_kind(hint.clone(), UnsupportedErrorKind::Format(hint)),
+    ))
+}
+
+/// Decodes `bytes` shrunk to fit in `max_edge` x `max_edge`, keeping the aspect ratio.
+/// `None` if the image already fits, which is checked from the header alone.
+pub(crate) fn downscale(bytes: &[u8], max_edge: u32) -> ImageResult<Option<DynamicImage>> {
+    let (width, height) = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()?;
+    if width.max(height) <= max_edge {
+        return Ok(None);
+    }
+    let image = image::load_from_memory(bytes)?;
+    Ok(Some(image.resize(max_edge, max_edge, FilterType::Triangle)))
+}
+
+/// Like [`downscale`], but re-encoded in the original format (PNG if that can't be
+/// written).
+pub(crate) fn downscale_encoded(bytes: &[u8], max_edge: u32) -> ImageResult<Option<Vec<u8>>> {
+    let Some(image) = downscale(bytes, max_edge)? else {
+        return Ok(None);
+    };
+    let format = image::guess_format(bytes)
+        .ok()
+        .filter(ImageFormat::writing_enabled)
+        .unwrap_or(ImageFormat::Png);
+    let mut encoded = Vec::new();
+    image.write_to(&mut Cursor::new(&mut encoded), format)?;
+    Ok(Some(encoded))
+}
+
+impl<'a> From<&'a Path> for ImageSource<'a> {
+    fn from(path: &'a Path) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl<'a> From<&'a [u8]> for ImageSource<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+/// Resolves an image reference (a path, an object key, a URL, ...) into bytes and an optional MIME type.
+///
+/// Both backends accept any implementation, so images can come from S3, a database or an
+/// HTTP cache without the crate depending on those clients.
+pub trait ImageLoader: Send + Sync {
+    fn load(&self, key: &str) -> impl Future<Output = io::Result<LoadedImage>> + Send;
+}
+
+/// Object-safe adapter for [`ImageLoader`], implemented for every loader. Lets sessions
+/// store a loader without becoming generic over it.
+pub trait BoxedImageLoader: Send + Sync {
+    fn load_boxed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<LoadedImage>> + Send + 'a>>;
+}
+
+impl<T: ImageLoader> BoxedImageLoader for T {
+    fn load_boxed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<LoadedImage>> + Send + 'a>> {
+        Box::pin(self.load(key))
+    }
+}
+
+/// Default loader: treats keys as filesystem paths, optionally relative to `root`.
+#[derive(Debug, Clone, Default)]
+pub struct FsImageLoader {
+    root: Option<PathBuf>,
+}
+
+impl FsImageLoader {
+    pub fn with_root(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: Some(root.into()),
+        }
+    }
+}
+
+impl ImageLoader for FsImageLoader {
+    async fn load(&self, key: &str) -> io::Result<LoadedImage> {
+        let path = self
+            .root
+            .as_ref()
+            .map_or_else(|| PathBuf::from(key), |root| root.join(key));
+        let bytes = fs::read(path).await?;
+        let mime_type = infer::get(&bytes).map(|kind| kind.mime_type().to_string());
+        Ok((bytes, mime_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_shaped_path_is_read_as_a_file() {
+        let source = ImageSource::Path(Path::new("https://example.com/missing.png"));
+        let err = source.read().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(source.describe(), "https://example.com/missing.png");
+    }
+}
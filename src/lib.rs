@@ -0,0 +1,14 @@
+#![allow(clippy::missing_errors_doc)]
+
+pub mod api;
+pub mod bench;
+pub mod bindings;
+pub mod grammar;
+pub mod images;
+pub mod registry;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod transcript;
+
+pub use bindings::{AsyncSession, MultimodalModel, ResponseStream, Session};
+pub use registry::ModelRegistry;
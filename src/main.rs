@@ -1,11 +1,17 @@
 #![allow(clippy::missing_errors_doc)]
 
+use arboard::Clipboard;
+use color_eyre::eyre::eyre;
+use image::{ImageFormat, RgbaImage};
+use qwen_llm_rs::images::ImageSource;
+use qwen_llm_rs::{api, bindings};
+use std::io::Cursor;
+use tracing::info;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, EnvFilter};
 
-mod api;
-mod bindings;
+const CAPTION_PROMPT: &str = "Caption this image in one paragraph. Respond with the caption only.";
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
@@ -15,6 +21,10 @@ async fn main() -> color_eyre::Result<()> {
         .init();
     color_eyre::install()?;
 
+    if std::env::args().any(|arg| arg == "--clipboard") {
+        return caption_clipboard();
+    }
+
     if false {
         bindings::run()?;
     } else {
@@ -23,3 +33,33 @@ async fn main() -> color_eyre::Result<()> {
 
     Ok(())
 }
+
+fn caption_clipboard() -> color_eyre::Result<()> {
+    let png = clipboard_png()?;
+    let model = bindings::MultimodalModel::load()?;
+    let mut session = model.new_session()?;
+    let caption = session
+        .chat_with(CAPTION_PROMPT)
+        .sources(&[ImageSource::Bytes(&png)])
+        .call()?;
+    info!("Clipboard: {caption}");
+    Ok(())
+}
+
+// arboard hands out raw RGBA pixels; encode them as PNG so they go through the same
+// in-memory image path as any other bytes.
+fn clipboard_png() -> color_eyre::Result<Vec<u8>> {
+    let image = Clipboard::new()?.get_image().map_err(|e| match e {
+        arboard::Error::ContentNotAvailable => eyre!("Clipboard does not contain an image"),
+        e => e.into(),
+    })?;
+    let rgba = RgbaImage::from_raw(
+        u32::try_from(image.width)?,
+        u32::try_from(image.height)?,
+        image.bytes.into_owned(),
+    )
+    .ok_or_else(|| eyre!("Clipboard image size doesn't match its pixel data"))?;
+    let mut png = Vec::new();
+    rgba.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
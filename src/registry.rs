@@ -0,0 +1,60 @@
+use crate::bindings::{MultimodalModel, Session};
+use color_eyre::eyre::{eyre, Result};
+use std::collections::HashMap;
+
+/// Keeps several loaded models by name, e.g. a fast 4B and a stronger 8B Qwen3-VL, so
+/// switching between them doesn't mean reloading from disk. All models share the one
+/// llama.cpp backend.
+#[derive(Default)]
+pub struct ModelRegistry {
+    models: HashMap<String, MultimodalModel>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `model` under `name`, returning the model it replaces.
+    pub fn insert(
+        &mut self,
+        name: impl Into<String>,
+        model: MultimodalModel,
+    ) -> Option<MultimodalModel> {
+        self.models.insert(name.into(), model)
+    }
+
+    /// The model named `name`, loaded with `load` the first time it's asked for, e.g.
+    /// `registry.get_or_load("8b", || MultimodalModel::builder().model_path(...).load())`.
+    pub fn get_or_load(
+        &mut self,
+        name: &str,
+        load: impl FnOnce() -> Result<MultimodalModel>,
+    ) -> Result<&MultimodalModel> {
+        if !self.models.contains_key(name) {
+            self.models.insert(name.to_string(), load()?);
+        }
+        Ok(&self.models[name])
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MultimodalModel> {
+        self.models.get(name)
+    }
+
+    /// Unloads the model named `name`, once no session uses it anymore.
+    pub fn remove(&mut self, name: &str) -> Option<MultimodalModel> {
+        self.models.remove(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.models.keys().map(String::as_str)
+    }
+
+    /// A new session with default settings on the model named `name`. Use
+    /// [`Session::with_model`] on [`ModelRegistry::get`] for other settings.
+    pub fn new_session(&self, name: &str) -> Result<Session<'_>> {
+        self.get(name)
+            .ok_or_else(|| eyre!("No model named {name:?} is loaded"))?
+            .new_session()
+    }
+}
@@ -0,0 +1,164 @@
+use crate::api::{Message, MessageContent, MessagePart};
+use crate::bindings::MultimodalModel;
+use crate::images::ImageSource;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use color_eyre::eyre::Result;
+use futures_util::{StreamExt, TryStreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+#[derive(Deserialize)]
+struct CompletionRequest {
+    #[serde(default)]
+    model: Option<String>,
+    messages: Vec<Message>,
+    #[serde(default)]
+    stream: bool,
+}
+
+/// `POST /v1/chat/completions`, OpenAI-compatible, answered by `model`.
+///
+/// Web frontends (and [`LlamaClient`](crate::api::LlamaClient)) can use it like
+/// `llama-server`, streaming or not. Mount it in an existing axum app, or run it with
+/// [`serve`].
+///
+/// Each request gets a fresh session that answers its last user message; earlier turns
+/// aren't replayed. Images must be `data:` URLs.
+pub fn router(model: Arc<MultimodalModel>) -> Router {
+    Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .with_state(model)
+}
+
+/// Serves [`router`] on `addr` until the process exits.
+pub async fn serve(model: Arc<MultimodalModel>, addr: impl ToSocketAddrs) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    axum::serve(listener, router(model)).await?;
+    Ok(())
+}
+
+async fn chat_completions(
+    State(model): State<Arc<MultimodalModel>>,
+    Json(request): Json<CompletionRequest>,
+) -> Response {
+    let (prompt, images) = match last_user_turn(&request.messages) {
+        Ok(turn) => turn,
+        Err(message) => return error_response(StatusCode::BAD_REQUEST, message),
+    };
+    let completion = Completion::new(
+        request
+            .model
+            .or_else(|| model.name())
+            .unwrap_or_else(|| "local".to_string()),
+    );
+    let mut pieces = Box::pin(model.async_session().stream_chat_sources(&prompt, &images));
+    if request.stream {
+        // A failed answer ends after its error event, without a finish reason or `[DONE]`,
+        // so clients don't take it for a complete one.
+        let events = async_stream::stream! {
+            while let Some(piece) = pieces.next().await {
+                match piece {
+                    Ok(piece) => yield completion.chunk(&json!({ "content": piece }), None),
+                    Err(e) => {
+                        yield Event::default()
+                            .data(json!({ "error": { "message": e.to_string() } }).to_string());
+                        return;
+                    }
+                }
+            }
+            yield completion.chunk(&json!({}), Some("stop"));
+            yield Event::default().data("[DONE]");
+        };
+        return Sse::new(events.map(Ok::<_, Infallible>))
+            .keep_alive(KeepAlive::default())
+            .into_response();
+    }
+    match pieces.try_collect::<String>().await {
+        Ok(content) => Json(completion.full(&content)).into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+// The text and images of the last user message.
+fn last_user_turn(messages: &[Message]) -> Result<(String, Vec<ImageSource<'_>>), &'static str> {
+    let message = messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .ok_or("Request has no user message")?;
+    let parts = match &message.content {
+        MessageContent::Text(text) => return Ok((text.clone(), Vec::new())),
+        MessageContent::Parts(parts) => parts,
+    };
+    let mut text = Vec::new();
+    let mut images = Vec::new();
+    for part in parts {
+        match part {
+            MessagePart::Text { text: part } => text.push(part.as_str()),
+            // Anything else would be read from the server's disk or network.
+            MessagePart::ImageUrl { image_url } if image_url.url.starts_with("data:") => {
+                images.push(ImageSource::DataUrl(&image_url.url));
+            }
+            MessagePart::ImageUrl { .. } => return Err("Images must be data: URLs"),
+        }
+    }
+    Ok((text.join("\n"), images))
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    let body = json!({ "error": { "message": message, "code": status.as_u16() } });
+    (status, Json(body)).into_response()
+}
+
+struct Completion {
+    id: String,
+    model: String,
+    created: u64,
+}
+
+impl Completion {
+    fn new(model: String) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            id: format!("chatcmpl-{}", now.as_nanos()),
+            model,
+            created: now.as_secs(),
+        }
+    }
+
+    fn chunk(&self, delta: &Value, finish_reason: Option<&str>) -> Event {
+        let chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        });
+        Event::default().data(chunk.to_string())
+    }
+
+    fn full(&self, content: &str) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop",
+            }],
+        })
+    }
+}
@@ -0,0 +1,72 @@
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// One completed generation, written as a single JSON line.
+#[derive(Serialize, Debug, Clone)]
+pub struct TranscriptRecord {
+    /// Milliseconds since the Unix epoch at the time the request was made.
+    pub timestamp_ms: u64,
+    /// `"local"` or `"http"`.
+    pub backend: &'static str,
+    pub prompt: String,
+    /// Image paths or keys; in-memory images are recorded by content hash.
+    pub images: Vec<String>,
+    pub parameters: serde_json::Value,
+    pub output: String,
+}
+
+impl TranscriptRecord {
+    pub(crate) fn new(
+        backend: &'static str,
+        prompt: &str,
+        images: Vec<String>,
+        parameters: serde_json::Value,
+    ) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX));
+        Self {
+            timestamp_ms,
+            backend,
+            prompt: prompt.to_string(),
+            images,
+            parameters,
+            output: String::new(),
+        }
+    }
+}
+
+/// Appends a JSONL audit trail of every generation. Attach the same logger (in an `Arc`)
+/// to local `Session`s and HTTP `ChatSession`s to get one uniform transcript.
+pub struct TranscriptLogger {
+    file: Mutex<File>,
+}
+
+impl TranscriptLogger {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn log(&self, record: &TranscriptRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        file.write_all(&line)
+    }
+
+    // A failing audit log shouldn't lose the answer itself, so generation paths only warn.
+    pub(crate) fn log_output(&self, mut record: TranscriptRecord, output: &str) {
+        output.clone_into(&mut record.output);
+        if let Err(e) = self.log(&record) {
+            warn!("Failed to write transcript record: {e}");
+        }
+    }
+}